@@ -0,0 +1,609 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use regex::{Regex, RegexBuilder};
+use simplelog::LevelFilter;
+
+use crate::context::Context;
+use crate::control::{Controller, Error, RequestClass, Result};
+use crate::logger;
+use crate::output::{
+    AccessPointMode, ConnectionStatus, Directory, Format, LogFilter, LogLevel, Progress, Settings,
+    TimeoutList, Tree, Versions,
+};
+
+pub(crate) fn execute(ctx: &Context, words: &[String]) -> Result<()> {
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        ["version"] => version(ctx),
+        ["format"] => format(ctx, None),
+        ["format", value] => format(ctx, Some(value)),
+        ["config", "show"] => config_show(ctx),
+        ["config", "set", key, value] => config_set(ctx, key, value),
+        ["log", "show"] => log_show(ctx),
+        ["log", "level"] => log_level(ctx, None),
+        ["log", "level", level] => log_level(ctx, Some(level)),
+        ["log", "file", "off"] => {
+            ctx.log.close_file();
+            Ok(())
+        }
+        ["log", "file", path] => log_file(ctx, path, None),
+        ["log", "file", path, level] => log_file(ctx, path, Some(level)),
+        ["log", "module", module, value] => {
+            log_module(ctx, module, parse_bool(value, "on", "off")?)
+        }
+        ["timeout"] => timeout(ctx, None, None),
+        ["timeout", class] => timeout(ctx, Some(class), None),
+        ["timeout", class, value] => timeout(ctx, Some(class), Some(value)),
+        ["wait", rest @ ..] => wait(ctx, timeout_arg(rest)?),
+        ["connection", rest @ ..] => connection(ctx, timeout_arg(rest)?),
+        ["ap", "auto"] => ap_auto(ctx, None),
+        ["ap", "auto", value] => ap_auto(ctx, Some(parse_bool(value, "on", "off")?)),
+        ["info", "about", rest @ ..] => info_about(ctx, timeout_arg(rest)?),
+        ["info", "memory", rest @ ..] => info_memory(ctx, timeout_arg(rest)?),
+        ["info", "flash", rest @ ..] => info_flash(ctx, timeout_arg(rest)?),
+        ["network", "scan", rest @ ..] => network_scan(ctx, timeout_arg(rest)?),
+        ["network", "list", rest @ ..] => network_list(ctx, timeout_arg(rest)?),
+        ["network", "add", ssid, key, rest @ ..] => network_add(
+            ctx,
+            (*ssid).to_owned(),
+            (*key).to_owned(),
+            timeout_arg(rest)?,
+        ),
+        ["network", "remove", ssid, rest @ ..] => {
+            network_remove(ctx, (*ssid).to_owned(), timeout_arg(rest)?)
+        }
+        ["sync", "files", "&" | "--background"] => sync_files_background(ctx),
+        ["sync", "tags", "&" | "--background"] => sync_tags_background(ctx),
+        ["sync", "files", rest @ ..] => sync_files(ctx, timeout_arg(rest)?),
+        ["sync", "tags", rest @ ..] => sync_tags(ctx, timeout_arg(rest)?),
+        ["jobs"] | ["jobs", "list"] => jobs(ctx),
+        ["jobs", "wait"] => jobs_wait(ctx, None),
+        ["jobs", "wait", id] => jobs_wait(ctx, Some(id)),
+        ["jobs", "cancel", id] => jobs_cancel(ctx, id),
+        ["fs", "pwd"] => fs_pwd(ctx),
+        ["fs", "cd", dir] => fs_cd(ctx, dir),
+        ["fs", "ls"] => fs_ls(ctx),
+        ["fs", "tree", rest @ ..] => fs_tree(ctx, rest),
+        ["fs", "find", rest @ ..] => fs_find(ctx, rest),
+        ["source", path] => source(ctx, path, false),
+        ["source", path, "--keep-going" | "keep-going"] => source(ctx, path, true),
+        _ => Err(Error::InvalidArgument(format!(
+            "unknown command: {}",
+            words.join(" ")
+        ))),
+    }
+}
+
+/// Number of scripts that may source each other, so that a script sourcing
+/// itself fails instead of overflowing the stack.
+const MAX_SCRIPT_DEPTH: usize = 10;
+
+/// Runs the commands of a script file line by line, skipping empty lines and
+/// `#` comments. Stops at the first failing line unless `keep_going` is set,
+/// in which case every failure is reported and the last one is returned.
+/// Ctrl-C always stops the script, also when it hits a command that does not
+/// wait for the device.
+pub(crate) fn source(ctx: &Context, path: &str, keep_going: bool) -> Result<()> {
+    let depth = ctx.script_depth();
+    if depth == MAX_SCRIPT_DEPTH {
+        return Err(Error::InvalidArgument(format!(
+            "scripts nested deeper than {MAX_SCRIPT_DEPTH} levels"
+        )));
+    }
+    ctx.set_script_depth(depth + 1);
+    let result = run_script(ctx, path, keep_going);
+    ctx.set_script_depth(depth);
+    result
+}
+
+fn run_script(ctx: &Context, path: &str, keep_going: bool) -> Result<()> {
+    let script = fs::read_to_string(path)?;
+    let mut failed = None;
+    for (n, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = match split(line).and_then(|words| execute(ctx, &words)) {
+            Err(e) if e.is_interrupted() => Err(e),
+            _ if ctx.ctrl.take_interrupt() => Err(Error::Interrupted(None)),
+            result => result,
+        };
+        if let Err(e) = result {
+            let e = Error::Script {
+                path: path.to_owned(),
+                line: n + 1,
+                error: Box::new(e),
+            };
+            if !keep_going || e.is_interrupted() {
+                return Err(e);
+            }
+            if let Some(previous) = failed.replace(e) {
+                ctx.print_error(&previous);
+            }
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+pub(crate) fn split(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_default();
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidArgument("unterminated quote".into()));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Extracts the optional per-invocation timeout following a command, given
+/// either as `<duration>` or as `--timeout <duration>`.
+fn timeout_arg<'a>(rest: &[&'a str]) -> Result<Option<&'a str>> {
+    match rest {
+        [] => Ok(None),
+        ["--timeout"] => Err(Error::InvalidArgument("missing value for --timeout".into())),
+        [value] | ["--timeout", value] => Ok(Some(value)),
+        _ => Err(Error::InvalidArgument(format!(
+            "unexpected arguments: {}",
+            rest.join(" ")
+        ))),
+    }
+}
+
+/// Parses a duration like `500ms`, `10s`, `5m` or `1h`. A plain number is
+/// taken as seconds.
+pub(crate) fn parse_duration(value: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidArgument(format!("invalid duration: {value}"));
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_mins(number)),
+        "h" => Ok(Duration::from_hours(number)),
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn parse_timeout(timeout: Option<&str>) -> Result<Option<Duration>> {
+    timeout.map(parse_duration).transpose()
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    level.parse().map_err(|_| {
+        Error::InvalidArgument(format!(
+            "invalid log level: {level}, expected off, error, warn, info, debug or trace"
+        ))
+    })
+}
+
+fn parse_bool(value: &str, on: &str, off: &str) -> Result<bool> {
+    if value == on {
+        Ok(true)
+    } else if value == off {
+        Ok(false)
+    } else {
+        Err(Error::InvalidArgument(format!(
+            "expected '{on}' or '{off}', got '{value}'"
+        )))
+    }
+}
+
+pub(crate) fn version(ctx: &Context) -> Result<()> {
+    ctx.print(&Versions(vec![
+        (
+            env!("CARGO_PKG_NAME").to_owned(),
+            env!("VERSION").to_owned(),
+        ),
+        (smart_repl::NAME.to_owned(), smart_repl::VERSION.to_owned()),
+        (
+            Controller::backend_name().to_owned(),
+            Controller::backend_version().to_owned(),
+        ),
+    ]))
+}
+
+pub(crate) fn format(ctx: &Context, format: Option<&str>) -> Result<()> {
+    if let Some(format) = format {
+        ctx.set_format(format.parse()?);
+        Ok(())
+    } else {
+        ctx.print(&ctx.format())
+    }
+}
+
+pub(crate) fn config_show(ctx: &Context) -> Result<()> {
+    ctx.print(&Settings(ctx.config()))
+}
+
+pub(crate) fn config_set(ctx: &Context, key: &str, value: &str) -> Result<()> {
+    ctx.set_config(key, value)
+}
+
+pub(crate) fn log_show(ctx: &Context) -> Result<()> {
+    ctx.print(&LogFilter {
+        level: ctx.log.level(),
+        file: ctx.log.file(),
+        modules: ctx.log.modules(),
+    })
+}
+
+pub(crate) fn log_level(ctx: &Context, level: Option<&str>) -> Result<()> {
+    if let Some(level) = level {
+        ctx.log.set_level(parse_level(level)?);
+        Ok(())
+    } else {
+        ctx.print(&LogLevel(ctx.log.level()))
+    }
+}
+
+/// Writes the log to a file as well, by default at debug level.
+pub(crate) fn log_file(ctx: &Context, path: &str, level: Option<&str>) -> Result<()> {
+    let level = level.map_or(Ok(logger::DEFAULT_FILE_LEVEL), parse_level)?;
+    ctx.log.set_file(Path::new(path), level)?;
+    Ok(())
+}
+
+/// Switches logging of a module and its submodules on or off, overriding
+/// the rule of any parent module.
+pub(crate) fn log_module(ctx: &Context, module: &str, enabled: bool) -> Result<()> {
+    if module.is_empty() {
+        return Err(Error::InvalidArgument("empty module path".into()));
+    }
+    ctx.log.set_module(module, enabled);
+    Ok(())
+}
+
+pub(crate) fn timeout(ctx: &Context, class: Option<&str>, value: Option<&str>) -> Result<()> {
+    match (class, value) {
+        (Some(class), Some(value)) => {
+            ctx.ctrl.set_timeout(class.parse()?, parse_duration(value)?);
+            Ok(())
+        }
+        (Some(class), None) => {
+            let class: RequestClass = class.parse()?;
+            ctx.print(&TimeoutList(vec![(class, ctx.ctrl.timeouts().get(class))]))
+        }
+        _ => {
+            let timeouts = ctx.ctrl.timeouts();
+            ctx.print(&TimeoutList(
+                RequestClass::ALL
+                    .into_iter()
+                    .map(|class| (class, timeouts.get(class)))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+pub(crate) fn ap_auto(ctx: &Context, value: Option<bool>) -> Result<()> {
+    if let Some(val) = value {
+        ctx.ctrl.set_access_point_mode(val);
+        Ok(())
+    } else {
+        ctx.print(&AccessPointMode(ctx.ctrl.get_access_point_mode()))
+    }
+}
+
+/// Waits until the device is connected, by default without a time limit.
+pub(crate) fn wait(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.ctrl.wait_connected(parse_timeout(timeout)?)
+}
+
+pub(crate) fn connection(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    let timeout = parse_timeout(timeout)?;
+    let state = ctx.ctrl.connection_state();
+    let connection = if state.connected {
+        Some(ctx.ctrl.get_info_connection(timeout)?)
+    } else {
+        None
+    };
+    ctx.print(&ConnectionStatus(state, connection))
+}
+
+pub(crate) fn info_about(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.print(&ctx.ctrl.get_info_about(parse_timeout(timeout)?)?)
+}
+
+pub(crate) fn info_memory(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.print(&ctx.ctrl.get_info_memory(parse_timeout(timeout)?)?)
+}
+
+pub(crate) fn info_flash(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.print(&ctx.ctrl.get_info_spiflash(parse_timeout(timeout)?)?)
+}
+
+pub(crate) fn network_scan(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.print(
+        ctx.ctrl
+            .get_wifi_scan_result(parse_timeout(timeout)?)?
+            .as_slice(),
+    )
+}
+
+pub(crate) fn network_list(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.print(
+        ctx.ctrl
+            .get_wifi_network_list(parse_timeout(timeout)?)?
+            .as_slice(),
+    )
+}
+
+pub(crate) fn network_add(
+    ctx: &Context,
+    ssid: String,
+    key: String,
+    timeout: Option<&str>,
+) -> Result<()> {
+    ctx.ctrl
+        .set_wifi_network(ssid, key, parse_timeout(timeout)?)
+}
+
+pub(crate) fn network_remove(ctx: &Context, ssid: String, timeout: Option<&str>) -> Result<()> {
+    ctx.ctrl.delete_wifi_network(ssid, parse_timeout(timeout)?)
+}
+
+pub(crate) fn sync_files(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    ctx.ctrl.sync_files(parse_timeout(timeout)?)
+}
+
+pub(crate) fn sync_tags(ctx: &Context, timeout: Option<&str>) -> Result<()> {
+    let timeout = parse_timeout(timeout)?;
+    let mut progress = Progress::new("tags".into(), ctx.format() == Format::Text);
+    let result = ctx.ctrl.sync_tags(timeout, |n, t| progress.update(n, t));
+    progress.finish();
+    result
+}
+
+pub(crate) fn sync_files_background(ctx: &Context) -> Result<()> {
+    check_background(ctx)?;
+    ctx.print(&ctx.ctrl.start_sync_files()?)
+}
+
+pub(crate) fn sync_tags_background(ctx: &Context) -> Result<()> {
+    check_background(ctx)?;
+    ctx.print(&ctx.ctrl.start_sync_tags()?)
+}
+
+/// Background syncs are tracked by the session, which ends right away unless
+/// it is interactive.
+fn check_background(ctx: &Context) -> Result<()> {
+    if ctx.interactive() {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument(
+            "background syncs are only possible in the interactive shell".into(),
+        ))
+    }
+}
+
+/// Lists the background jobs. Finished jobs are listed once and then
+/// forgotten.
+pub(crate) fn jobs(ctx: &Context) -> Result<()> {
+    let jobs = ctx.ctrl.jobs();
+    ctx.print(jobs.as_slice())?;
+    for job in jobs.iter().filter(|job| job.finished()) {
+        ctx.ctrl.remove_job(job.id);
+    }
+    Ok(())
+}
+
+/// Waits for the given job, or for all jobs one after the other. Every failed
+/// job is reported and the last failure is returned.
+pub(crate) fn jobs_wait(ctx: &Context, id: Option<&str>) -> Result<()> {
+    let ids = match id {
+        Some(id) => vec![parse_job_id(id)?],
+        None => ctx.ctrl.jobs().iter().map(|job| job.id).collect(),
+    };
+    let mut failed = None;
+    for id in ids {
+        let mut progress = Progress::new(format!("[{id}]"), ctx.format() == Format::Text);
+        let result = ctx.ctrl.wait_job(id, |n, t| progress.update(n, t));
+        progress.finish();
+        match result {
+            Err(e @ Error::Interrupted(_)) => return Err(e),
+            Err(e) => {
+                if let Some(e) = failed.replace(e) {
+                    ctx.print_error(&e);
+                }
+            }
+            Ok(()) => {}
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Forgets a background sync. The device goes on with a running sync anyway.
+pub(crate) fn jobs_cancel(ctx: &Context, id: &str) -> Result<()> {
+    ctx.ctrl.cancel_job(parse_job_id(id)?)
+}
+
+fn parse_job_id(id: &str) -> Result<u64> {
+    id.parse()
+        .map_err(|_| Error::InvalidArgument(format!("invalid job id: {id}")))
+}
+
+pub(crate) fn fs_pwd(ctx: &Context) -> Result<()> {
+    ctx.print(&Directory(ctx.ctrl.current_directory()?))
+}
+
+pub(crate) fn fs_cd(ctx: &Context, dir: &str) -> Result<()> {
+    ctx.ctrl.change_directory(dir)
+}
+
+pub(crate) fn fs_ls(ctx: &Context) -> Result<()> {
+    ctx.print(&ctx.ctrl.directory_content()?)
+}
+
+/// Prints the directories below `[dir]` as a tree, down to `[--depth N]`
+/// levels. The current directory is restored afterwards, also on failure.
+pub(crate) fn fs_tree(ctx: &Context, args: &[&str]) -> Result<()> {
+    let (dir, depth) = match args {
+        [] => (None, None),
+        [dir] => (Some(*dir), None),
+        ["--depth", depth] => (None, Some(*depth)),
+        [dir, "--depth", depth] | ["--depth", depth, dir] => (Some(*dir), Some(*depth)),
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "unexpected arguments: {}",
+                args.join(" ")
+            )));
+        }
+    };
+    let depth = depth
+        .map(|depth| {
+            depth
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidArgument(format!("invalid depth: {depth}")))
+        })
+        .transpose()?;
+    let start = ctx.ctrl.directory_path()?;
+    let tree = dir
+        .map_or(Ok(()), |dir| ctx.ctrl.change_directory(dir))
+        .and_then(|()| {
+            let name = ctx.ctrl.current_directory()?;
+            walk(ctx, if name.is_empty() { "/".into() } else { name }, depth)
+        });
+    ctx.ctrl.set_directory_path(&start)?;
+    ctx.print(&tree?)
+}
+
+/// Pattern for `fs find`, either a glob with `*` and `?` or a regular
+/// expression, both ignoring case.
+enum Pattern {
+    Glob(Vec<char>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(pattern) => glob(pattern, &name.to_lowercase().chars().collect::<Vec<_>>()),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Prints the paths of all directories and tracks whose name matches the
+/// pattern, below the root or the directory given with `--in`, as
+/// `<pattern> [glob|regex] [--in <dir>]`. Directories end with a `/`.
+pub(crate) fn fs_find(ctx: &Context, args: &[&str]) -> Result<()> {
+    let (pattern, mode, dir) = match args {
+        [pattern] => (*pattern, None, None),
+        [pattern, "--in", dir] => (*pattern, None, Some(*dir)),
+        [pattern, mode] => (*pattern, Some(*mode), None),
+        [pattern, mode, "--in", dir] => (*pattern, Some(*mode), Some(*dir)),
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "unexpected arguments: {}",
+                args.join(" ")
+            )));
+        }
+    };
+    let regex = mode
+        .map(|mode| parse_bool(mode, "regex", "glob"))
+        .transpose()?
+        .unwrap_or(false);
+    let pattern = if regex {
+        Pattern::Regex(
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| Error::InvalidArgument(format!("invalid regex: {e}")))?,
+        )
+    } else {
+        Pattern::Glob(pattern.to_lowercase().chars().collect())
+    };
+    let start = ctx.ctrl.directory_path()?;
+    let mut found = Vec::new();
+    let result = ctx
+        .ctrl
+        .change_directory(dir.unwrap_or("/"))
+        .and_then(|()| search(ctx, &pattern, &mut ctx.ctrl.directory_path()?, &mut found));
+    ctx.ctrl.set_directory_path(&start)?;
+    result?;
+    ctx.print(found.as_slice())
+}
+
+fn search(
+    ctx: &Context,
+    pattern: &Pattern,
+    path: &mut Vec<String>,
+    found: &mut Vec<String>,
+) -> Result<()> {
+    let content = ctx.ctrl.directory_content()?;
+    let dir = path.iter().fold(String::new(), |dir, d| dir + "/" + d);
+    for track in content.tracks.iter().filter(|track| pattern.matches(track)) {
+        found.push(format!("{dir}/{track}"));
+    }
+    for name in content.dirs {
+        if pattern.matches(&name) {
+            found.push(format!("{dir}/{name}/"));
+        }
+        ctx.ctrl.enter_directory(&name)?;
+        path.push(name);
+        let result = search(ctx, pattern, path, found);
+        path.pop();
+        ctx.ctrl.leave_directory()?;
+        result?;
+    }
+    Ok(())
+}
+
+/// Matches `name` against a glob `pattern`, backtracking to the last `*`
+/// on a mismatch.
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn walk(ctx: &Context, name: String, depth: Option<usize>) -> Result<Tree> {
+    let content = ctx.ctrl.directory_content()?;
+    let mut dirs = Vec::new();
+    if depth != Some(0) {
+        for dir in content.dirs {
+            ctx.ctrl.enter_directory(&dir)?;
+            let tree = walk(ctx, dir, depth.map(|depth| depth - 1));
+            ctx.ctrl.leave_directory()?;
+            dirs.push(tree?);
+        }
+    }
+    Ok(Tree {
+        name,
+        tracks: content.tracks.len(),
+        cover: content.cover,
+        dirs,
+    })
+}
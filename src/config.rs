@@ -0,0 +1,256 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use simplelog::LevelFilter;
+
+use crate::command;
+use crate::control::{Error, RequestClass, Result};
+use crate::output::Format;
+
+const DEFAULT_PROMPT: &str = ">> ";
+
+/// Keys accepted by [`Config::set`], in the order they are shown.
+pub(crate) const KEYS: [&str; 12] = [
+    "format",
+    "prompt",
+    "color",
+    "ap_auto",
+    "timeouts.info",
+    "timeouts.network",
+    "timeouts.files",
+    "timeouts.tags",
+    "log.level",
+    "log.ignore",
+    "log.file",
+    "log.file_level",
+];
+
+/// Defaults of the CLI read from a TOML file. Every setting is optional, so
+/// only values that have been set are written back.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ap_auto: Option<bool>,
+    timeouts: Timeouts,
+    log: Log,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Timeouts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Log {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_level: Option<String>,
+}
+
+impl Config {
+    /// Returns `$XDG_CONFIG_HOME/audio-cli/config.toml`, falling back to
+    /// `~/.config` if the variable is not set.
+    pub(crate) fn default_path() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+    }
+
+    /// Reads the configuration from `path`. A missing file is an empty
+    /// configuration.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let config: Self = toml::from_str(&content)
+            .map_err(|e| Error::InvalidArgument(format!("{}: {e}", path.display())))?;
+        for key in KEYS {
+            if let Some(value) = config.get(key) {
+                check(key, &value)
+                    .map_err(|e| Error::InvalidArgument(format!("{}: {e}", path.display())))?;
+            }
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Returns the value of a key as it is given to [`Config::set`].
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "ap_auto" => self
+                .ap_auto
+                .map(|auto| if auto { "on" } else { "off" }.to_owned()),
+            "log.ignore" => self.log.ignore.as_ref().map(|ignore| ignore.join(",")),
+            _ => self.value(key).and_then(Option::clone),
+        }
+    }
+
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        check(key, value)?;
+        match key {
+            "ap_auto" => self.ap_auto = Some(value == "on"),
+            "log.ignore" => {
+                self.log.ignore = Some(
+                    value
+                        .split(',')
+                        .filter(|module| !module.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                );
+            }
+            _ => *self.value_mut(key).unwrap() = Some(value.to_owned()),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn format(&self) -> Option<Format> {
+        self.format.as_deref().map(|format| format.parse().unwrap())
+    }
+
+    pub(crate) fn prompt(&self) -> &str {
+        self.prompt.as_deref().unwrap_or(DEFAULT_PROMPT)
+    }
+
+    /// Returns whether colors are forced on or off, `None` leaves it to the
+    /// terminal.
+    pub(crate) fn color(&self) -> Option<bool> {
+        match self.color.as_deref() {
+            Some("always") => Some(true),
+            Some("never") => Some(false),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn ap_auto(&self) -> Option<bool> {
+        self.ap_auto
+    }
+
+    pub(crate) fn timeouts(&self) -> Vec<(RequestClass, Duration)> {
+        RequestClass::ALL
+            .into_iter()
+            .filter_map(|class| {
+                let value = self.get(&format!("timeouts.{class}"))?;
+                Some((class, command::parse_duration(&value).unwrap()))
+            })
+            .collect()
+    }
+
+    pub(crate) fn log_level(&self) -> Option<LevelFilter> {
+        self.log
+            .level
+            .as_deref()
+            .map(|level| level.parse().unwrap())
+    }
+
+    pub(crate) fn log_ignore(&self) -> Option<&[String]> {
+        self.log.ignore.as_deref()
+    }
+
+    pub(crate) fn log_file(&self) -> Option<&str> {
+        self.log.file.as_deref()
+    }
+
+    pub(crate) fn log_file_level(&self) -> Option<LevelFilter> {
+        self.log
+            .file_level
+            .as_deref()
+            .map(|level| level.parse().unwrap())
+    }
+
+    fn value(&self, key: &str) -> Option<&Option<String>> {
+        match key {
+            "format" => Some(&self.format),
+            "prompt" => Some(&self.prompt),
+            "color" => Some(&self.color),
+            "timeouts.info" => Some(&self.timeouts.info),
+            "timeouts.network" => Some(&self.timeouts.network),
+            "timeouts.files" => Some(&self.timeouts.files),
+            "timeouts.tags" => Some(&self.timeouts.tags),
+            "log.level" => Some(&self.log.level),
+            "log.file" => Some(&self.log.file),
+            "log.file_level" => Some(&self.log.file_level),
+            _ => None,
+        }
+    }
+
+    fn value_mut(&mut self, key: &str) -> Option<&mut Option<String>> {
+        match key {
+            "format" => Some(&mut self.format),
+            "prompt" => Some(&mut self.prompt),
+            "color" => Some(&mut self.color),
+            "timeouts.info" => Some(&mut self.timeouts.info),
+            "timeouts.network" => Some(&mut self.timeouts.network),
+            "timeouts.files" => Some(&mut self.timeouts.files),
+            "timeouts.tags" => Some(&mut self.timeouts.tags),
+            "log.level" => Some(&mut self.log.level),
+            "log.file" => Some(&mut self.log.file),
+            "log.file_level" => Some(&mut self.log.file_level),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that `value` is valid for `key`, so the typed getters can rely on
+/// it.
+fn check(key: &str, value: &str) -> Result<()> {
+    let invalid = |expected: &str| {
+        Err(Error::InvalidArgument(format!(
+            "invalid value for {key}: {value}, expected {expected}"
+        )))
+    };
+    match key {
+        "format" => value.parse::<Format>().map(|_| ()),
+        "prompt" | "log.ignore" | "log.file" => Ok(()),
+        "color" => match value {
+            "auto" | "always" | "never" => Ok(()),
+            _ => invalid("auto, always or never"),
+        },
+        "ap_auto" => match value {
+            "on" | "off" => Ok(()),
+            _ => invalid("on or off"),
+        },
+        "timeouts.info" | "timeouts.network" | "timeouts.files" | "timeouts.tags" => {
+            command::parse_duration(value).map(|_| ())
+        }
+        "log.level" | "log.file_level" if value.parse::<LevelFilter>().is_ok() => Ok(()),
+        "log.level" | "log.file_level" => invalid("off, error, warn, info, debug or trace"),
+        _ => Err(Error::InvalidArgument(format!("unknown setting: {key}"))),
+    }
+}
@@ -0,0 +1,167 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::config::Config;
+use crate::control::{Controller, Error, RequestClass, Result};
+use crate::logger::LogControl;
+use crate::output;
+use crate::output::{Format, Render};
+
+/// State shared by all commands of a session.
+pub(crate) struct Context {
+    pub(crate) ctrl: Controller,
+    pub(crate) log: LogControl,
+    format: Cell<Format>,
+    prompt: String,
+    config: RefCell<Config>,
+    config_path: Option<PathBuf>,
+    interactive: bool,
+    /// Number of scripts being run, each sourced by the one before.
+    script_depth: Cell<usize>,
+}
+
+impl Context {
+    /// Creates the context and applies the defaults from `config`.
+    pub(crate) fn new(
+        ctrl: Controller,
+        log: LogControl,
+        config: Config,
+        config_path: Option<PathBuf>,
+        interactive: bool,
+    ) -> Self {
+        let ctx = Self {
+            ctrl,
+            log,
+            format: Cell::new(Format::default()),
+            prompt: config.prompt().to_owned(),
+            config: RefCell::new(Config::default()),
+            config_path,
+            interactive,
+            script_depth: Cell::new(0),
+        };
+        ctx.apply(&config, None);
+        ctx.config.replace(config);
+        ctx
+    }
+
+    /// Returns whether the session is the interactive shell, which outlives a
+    /// single command.
+    pub(crate) fn interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub(crate) fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    /// Returns the settings in effect, including defaults, command line
+    /// options and changes made in this session. The log file settings are
+    /// left out while no log file is written.
+    pub(crate) fn config(&self) -> Vec<(String, String)> {
+        let config = self.config.borrow();
+        let timeouts = self.ctrl.timeouts();
+        let file = self.log.file();
+        config::KEYS
+            .into_iter()
+            .filter_map(|key| {
+                let value = match key {
+                    "format" => self.format().to_string(),
+                    "prompt" => self.prompt.clone(),
+                    "color" => config.get(key).unwrap_or_else(|| "auto".into()),
+                    "ap_auto" => if self.ctrl.get_access_point_mode() {
+                        "on"
+                    } else {
+                        "off"
+                    }
+                    .to_owned(),
+                    "log.level" => output::level(self.log.level()),
+                    "log.ignore" => self
+                        .log
+                        .modules()
+                        .into_iter()
+                        .filter(|(_, enabled)| !enabled)
+                        .map(|(module, _)| module)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    "log.file" => file.as_ref()?.0.display().to_string(),
+                    "log.file_level" => output::level(file.as_ref()?.1),
+                    key => {
+                        let class: RequestClass = key.strip_prefix("timeouts.")?.parse().ok()?;
+                        output::duration(timeouts.get(class))
+                    }
+                };
+                Some((key.to_owned(), value))
+            })
+            .collect()
+    }
+
+    /// Changes a setting in the configuration file. Format, timeouts and the
+    /// access point mode take effect at once, everything else at the next
+    /// start.
+    pub(crate) fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        let path = self
+            .config_path
+            .as_deref()
+            .ok_or_else(|| Error::InvalidArgument("no configuration file, use --config".into()))?;
+        let mut config = Config::load(path)?;
+        config.set(key, value)?;
+        config.save(path)?;
+        self.apply(&config, Some(key));
+        self.config.replace(config);
+        Ok(())
+    }
+
+    /// Applies the settings of `config` that can change at runtime, only
+    /// `key` if given.
+    fn apply(&self, config: &Config, key: Option<&str>) {
+        let applies = |k: &str| key.is_none_or(|key| key == k);
+        if applies("format")
+            && let Some(format) = config.format()
+        {
+            self.set_format(format);
+        }
+        for (class, timeout) in config.timeouts() {
+            if applies(&format!("timeouts.{class}")) {
+                self.ctrl.set_timeout(class, timeout);
+            }
+        }
+        if applies("ap_auto")
+            && let Some(auto) = config.ap_auto()
+        {
+            self.ctrl.set_access_point_mode(auto);
+        }
+    }
+
+    pub(crate) fn format(&self) -> Format {
+        self.format.get()
+    }
+
+    pub(crate) fn set_format(&self, format: Format) {
+        self.format.set(format);
+    }
+
+    pub(crate) fn script_depth(&self) -> usize {
+        self.script_depth.get()
+    }
+
+    pub(crate) fn set_script_depth(&self, depth: usize) {
+        self.script_depth.set(depth);
+    }
+
+    pub(crate) fn print(&self, value: &(impl Render + ?Sized)) -> Result<()> {
+        io::stdout()
+            .lock()
+            .write_all(&output::render(self.format(), value))
+            .map_err(Into::into)
+    }
+
+    pub(crate) fn print_error(&self, e: &Error) {
+        io::stderr()
+            .lock()
+            .write_all(&output::render(self.format(), e))
+            .ok();
+    }
+}
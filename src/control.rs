@@ -1,43 +1,147 @@
+use std::cell::Cell;
 use std::error;
 use std::fmt;
+use std::io;
+use std::mem;
 use std::result;
+use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{Builder, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use backend::DirectoryContent;
 use backend::{
-    About, Backend, ChangeDirectory, Connection, Event, FileSync, Memory, Network, SPIFlash,
-    TagSync,
+    About, ChangeDirectory, Connection, Event, FileSync, Memory, Network, SPIFlash, TagSync,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
+
+use crate::device::Device;
 
 pub(crate) type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug, Clone)]
 pub(crate) enum Error {
     Timeout,
+    Disconnected,
     Backend(backend::Error),
+    Aborted(Option<(usize, usize)>),
+    Interrupted(Option<(usize, usize)>),
+    /// Waiting for a sync was interrupted, but the device goes on with it.
+    Detached(Option<(usize, usize)>),
+    /// A sync of this class abandoned earlier still runs on the device.
+    Busy(RequestClass),
+    InvalidArgument(String),
+    Io(Arc<io::Error>),
+    Script {
+        path: String,
+        line: usize,
+        error: Box<Error>,
+    },
 }
 
 pub(crate) struct Controller {
-    backend: Backend,
+    device: Box<dyn Device>,
     handle: Option<JoinHandle<()>>,
     sender: Sender<Command>,
-    shared: Arc<(Mutex<SharedData>, Condvar)>,
+    shared: Arc<Shared>,
+    timeouts: Cell<Timeouts>,
+    connect_timeout: Cell<Duration>,
+}
+
+/// Requests grouped by the default timeout applied to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestClass {
+    Info,
+    Network,
+    Files,
+    Tags,
+}
+
+/// Whether the device is connected and how long ago this last changed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectionState {
+    pub(crate) connected: bool,
+    pub(crate) since: Option<Duration>,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    info: Duration,
+    network: Duration,
+    files: Duration,
+    tags: Duration,
+}
+
+/// Time after which an abandoned request that the device has not started is
+/// assumed to get no reply any more.
+const ABANDONED_LIFETIME: Duration = Duration::from_mins(1);
+
+type Shared = (Mutex<SharedData>, Condvar);
+
 #[derive(Default)]
 struct SharedData {
-    error: Option<Error>,
-    info_connection: Option<Connection>,
-    info_about: Option<About>,
-    info_memory: Option<Memory>,
-    info_spiflash: Option<SPIFlash>,
-    scan_result: Option<Vec<Network>>,
-    network_list: Option<Vec<String>>,
+    connected: bool,
+    changed: Option<Instant>,
+    listener: Option<Arc<dyn Fn(bool) + Send + Sync>>,
+    interrupted: bool,
+    next_id: u64,
+    pending: Vec<Pending>,
+}
+
+/// A request sent to the backend that is waiting for its reply. A request
+/// nobody waits for any more is kept as abandoned until its reply arrives.
+struct Pending {
+    id: u64,
+    request: Request,
+    reply: Option<Result<Reply>>,
+    progress: Option<(usize, usize)>,
+    started: bool,
+    background: bool,
+    abandoned: Option<Instant>,
+    sent: Instant,
+    /// Default timeout of the request's class. An abandoned request older
+    /// than this no longer takes replies from a request still waited for.
+    timeout: Duration,
+}
+
+/// A sync running in the background, see [`Controller::start_sync_files`].
+pub(crate) struct Job {
+    pub(crate) id: u64,
+    pub(crate) class: RequestClass,
+    pub(crate) state: JobState,
+}
+
+pub(crate) enum JobState {
+    Waiting,
+    Running(Option<(usize, usize)>),
+    Done,
+    Failed(Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    InfoConnection,
+    InfoAbout,
+    InfoMemory,
+    InfoSPIFlash,
+    WiFiScanResult,
+    WiFiNetworkList,
+    WiFiSetNetwork,
+    WiFiDeleteNetwork,
+    FileSync,
+    TagSync,
+}
+
+enum Reply {
+    InfoConnection(Connection),
+    InfoAbout(About),
+    InfoMemory(Memory),
+    InfoSPIFlash(SPIFlash),
+    WiFiScanResult(Vec<Network>),
+    WiFiNetworkList(Vec<String>),
+    Done,
 }
 
 enum Command {
@@ -45,14 +149,13 @@ enum Command {
 }
 
 impl Controller {
-    pub(crate) fn new() -> Self {
-        let backend = Backend::new();
-        let receiver = backend.receiver().unwrap();
+    pub(crate) fn new(device: Box<dyn Device>) -> Self {
+        let receiver = device.receiver().unwrap();
         let shared = Arc::new((Mutex::new(SharedData::default()), Condvar::new()));
         let shared_thread = shared.clone();
         let (sender, rx) = mpsc::channel();
         Self {
-            backend,
+            device,
             handle: Some(
                 Builder::new()
                     .name("control".into())
@@ -61,9 +164,27 @@ impl Controller {
             ),
             sender,
             shared,
+            timeouts: Cell::new(Timeouts::default()),
+            connect_timeout: Cell::new(Duration::ZERO),
         }
     }
 
+    pub(crate) fn timeouts(&self) -> Timeouts {
+        self.timeouts.get()
+    }
+
+    pub(crate) fn set_timeout(&self, class: RequestClass, timeout: Duration) {
+        let mut timeouts = self.timeouts.get();
+        timeouts.set(class, timeout);
+        self.timeouts.set(timeouts);
+    }
+
+    /// Sets how long a request waits for the device to connect before it
+    /// fails. By default it fails at once.
+    pub(crate) fn set_connect_timeout(&self, timeout: Duration) {
+        self.connect_timeout.set(timeout);
+    }
+
     pub(crate) fn backend_name() -> &'static str {
         backend::NAME
     }
@@ -72,175 +193,358 @@ impl Controller {
         backend::VERSION
     }
 
-    pub(crate) fn get_access_point_mode(&self) -> bool {
-        self.backend.get_access_point_mode()
+    pub(crate) fn connection_state(&self) -> ConnectionState {
+        let data = self.shared.0.lock().unwrap();
+        ConnectionState {
+            connected: data.connected,
+            since: data.changed.map(|changed| changed.elapsed()),
+        }
     }
 
-    pub(crate) fn set_access_point_mode(&self, auto: bool) {
-        self.backend.set_access_point_mode(auto);
+    /// Calls `listener` from the control thread whenever the device connects
+    /// or disconnects. It is called without holding the shared state, so it
+    /// may block without holding up requests.
+    pub(crate) fn on_connection_change(&self, listener: impl Fn(bool) + Send + Sync + 'static) {
+        self.shared.0.lock().unwrap().listener = Some(Arc::new(listener));
     }
 
-    pub(crate) fn get_info_connection(&self) -> Result<Connection> {
+    /// Blocks until the device is connected, without a limit if `timeout` is
+    /// `None`. Fails with [`Error::Timeout`] once the timeout has passed.
+    pub(crate) fn wait_connected(&self, timeout: Option<Duration>) -> Result<()> {
         let (mutex, cvar) = &*self.shared;
         let data = mutex.lock().unwrap();
-        self.backend.get_info_connection();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
+        let waiting = |data: &mut SharedData| !data.connected && !data.interrupted;
+        let mut data = match timeout {
+            Some(timeout) => cvar.wait_timeout_while(data, timeout, waiting).unwrap().0,
+            None => cvar.wait_while(data, waiting).unwrap(),
+        };
+        if data.connected {
+            Ok(())
+        } else if mem::take(&mut data.interrupted) {
+            Err(Error::Interrupted(None))
         } else {
-            data.info_connection
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+            Err(Error::Timeout)
         }
     }
 
-    pub(crate) fn get_info_about(&self) -> Result<About> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.get_info_about();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.info_about
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+    /// Returns a function that makes the request currently waited for return
+    /// with [`Error::Interrupted`], meant to be called from a Ctrl-C handler.
+    /// If nothing is waited for, the next wait returns at once, unless
+    /// [`Controller::take_interrupt`] is called first.
+    pub(crate) fn interrupter(&self) -> impl Fn() + Send + 'static {
+        let shared = self.shared.clone();
+        move || {
+            let (mutex, cvar) = &*shared;
+            mutex.lock().unwrap().interrupted = true;
+            cvar.notify_all();
         }
     }
 
-    pub(crate) fn get_info_memory(&self) -> Result<Memory> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.get_info_memory();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.info_memory
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+    /// Returns whether Ctrl-C was pressed since it was last seen, and forgets
+    /// it. Used between the lines of a script and before a shell command.
+    pub(crate) fn take_interrupt(&self) -> bool {
+        mem::take(&mut self.shared.0.lock().unwrap().interrupted)
+    }
+
+    pub(crate) fn get_access_point_mode(&self) -> bool {
+        self.device.get_access_point_mode()
+    }
+
+    pub(crate) fn set_access_point_mode(&self, auto: bool) {
+        self.device.set_access_point_mode(auto);
+    }
+
+    pub(crate) fn get_info_connection(&self, timeout: Option<Duration>) -> Result<Connection> {
+        match self.request(Request::InfoConnection, timeout, |device| {
+            device.get_info_connection();
+        })? {
+            Reply::InfoConnection(info) => Ok(info),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn get_info_spiflash(&self) -> Result<SPIFlash> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.get_info_spiflash();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.info_spiflash
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+    pub(crate) fn get_info_about(&self, timeout: Option<Duration>) -> Result<About> {
+        match self.request(Request::InfoAbout, timeout, |device| {
+            device.get_info_about();
+        })? {
+            Reply::InfoAbout(info) => Ok(info),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn get_wifi_scan_result(&self) -> Result<Vec<Network>> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.get_wifi_scan_result();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.scan_result
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+    pub(crate) fn get_info_memory(&self, timeout: Option<Duration>) -> Result<Memory> {
+        match self.request(Request::InfoMemory, timeout, |device| {
+            device.get_info_memory();
+        })? {
+            Reply::InfoMemory(info) => Ok(info),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn get_wifi_network_list(&self) -> Result<Vec<String>> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.get_wifi_network_list();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.network_list
-                .take()
-                .ok_or_else(|| data.error.take().unwrap())
+    pub(crate) fn get_info_spiflash(&self, timeout: Option<Duration>) -> Result<SPIFlash> {
+        match self.request(Request::InfoSPIFlash, timeout, |device| {
+            device.get_info_spiflash();
+        })? {
+            Reply::InfoSPIFlash(info) => Ok(info),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn set_wifi_network(&self, ssid: String, key: String) -> Result<()> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.set_wifi_network(ssid, key);
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.error.take().map_or(Ok(()), Err)
+    pub(crate) fn get_wifi_scan_result(&self, timeout: Option<Duration>) -> Result<Vec<Network>> {
+        match self.request(Request::WiFiScanResult, timeout, |device| {
+            device.get_wifi_scan_result();
+        })? {
+            Reply::WiFiScanResult(list) => Ok(list),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn delete_wifi_network(&self, ssid: String) -> Result<()> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.delete_wifi_network(ssid);
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(3)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.error.take().map_or(Ok(()), Err)
+    pub(crate) fn get_wifi_network_list(&self, timeout: Option<Duration>) -> Result<Vec<String>> {
+        match self.request(Request::WiFiNetworkList, timeout, |device| {
+            device.get_wifi_network_list();
+        })? {
+            Reply::WiFiNetworkList(list) => Ok(list),
+            _ => unreachable!(),
         }
     }
 
-    pub(crate) fn sync_files(&self) -> Result<()> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.sync_files();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_secs(10)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
+    pub(crate) fn set_wifi_network(
+        &self,
+        ssid: String,
+        key: String,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.request(Request::WiFiSetNetwork, timeout, |device| {
+            device.set_wifi_network(ssid, key);
+        })
+        .map(|_| ())
+    }
+
+    pub(crate) fn delete_wifi_network(
+        &self,
+        ssid: String,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.request(Request::WiFiDeleteNetwork, timeout, |device| {
+            device.delete_wifi_network(ssid);
+        })
+        .map(|_| ())
+    }
+
+    pub(crate) fn sync_files(&self, timeout: Option<Duration>) -> Result<()> {
+        self.request(Request::FileSync, timeout, |device| {
+            device.sync_files();
+        })
+        .map(|_| ())
+    }
+
+    /// Starts a file sync without waiting for it to complete. The sync is
+    /// tracked as a job until it is waited for or reported as finished.
+    pub(crate) fn start_sync_files(&self) -> Result<Job> {
+        let id = self.submit(Request::FileSync, true, |device| {
+            device.sync_files();
+        })?;
+        Ok(self.job(id).unwrap())
+    }
+
+    pub(crate) fn start_sync_tags(&self) -> Result<Job> {
+        let id = self.submit(Request::TagSync, true, |device| {
+            device.sync_tags();
+        })?;
+        Ok(self.job(id).unwrap())
+    }
+
+    pub(crate) fn jobs(&self) -> Vec<Job> {
+        let data = self.shared.0.lock().unwrap();
+        data.pending
+            .iter()
+            .filter(|p| p.background && p.abandoned.is_none())
+            .map(Pending::job)
+            .collect()
+    }
+
+    fn job(&self, id: u64) -> Option<Job> {
+        self.jobs().into_iter().find(|job| job.id == id)
+    }
+
+    /// Forgets a finished job after it has been reported.
+    pub(crate) fn remove_job(&self, id: u64) {
+        let mut data = self.shared.0.lock().unwrap();
+        data.pending
+            .retain(|p| p.id != id || !p.background || p.reply.is_none());
+    }
+
+    /// Stops tracking a job. The device cannot be told to stop a sync, so a
+    /// running one goes on and another sync of its kind is refused until it
+    /// has ended.
+    pub(crate) fn cancel_job(&self, id: u64) -> Result<()> {
+        let mut data = self.shared.0.lock().unwrap();
+        let index = data
+            .pending
+            .iter()
+            .position(|p| p.id == id && p.background && p.abandoned.is_none())
+            .ok_or_else(|| Error::InvalidArgument(format!("no such job: {id}")))?;
+        if data.pending[index].reply.is_some() {
+            data.pending.remove(index);
         } else {
-            data.error.take().map_or(Ok(()), Err)
+            data.pending[index].abandoned = Some(Instant::now());
         }
+        Ok(())
     }
 
-    pub(crate) fn sync_tags(&self) -> Result<()> {
-        let (mutex, cvar) = &*self.shared;
-        let data = mutex.lock().unwrap();
-        self.backend.sync_tags();
-        let (mut data, result) = cvar.wait_timeout(data, Duration::from_mins(10)).unwrap();
-        if result.timed_out() {
-            Err(Error::Timeout)
-        } else {
-            data.error.take().map_or(Ok(()), Err)
+    /// Waits for a job to finish and returns its result. The job is forgotten
+    /// afterwards unless the wait is interrupted.
+    pub(crate) fn wait_job(&self, id: u64, progress: impl FnMut(usize, usize)) -> Result<()> {
+        if self.job(id).is_none() {
+            return Err(Error::InvalidArgument(format!("no such job: {id}")));
         }
+        self.wait(id, None, progress).map(|_| ())
+    }
+
+    /// Syncs the tags, calling `progress` with the number of synced and total
+    /// files whenever the backend reports a step.
+    pub(crate) fn sync_tags(
+        &self,
+        timeout: Option<Duration>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        self.request_with_progress(
+            Request::TagSync,
+            timeout,
+            |device| {
+                device.sync_tags();
+            },
+            progress,
+        )
+        .map(|_| ())
     }
 
     pub(crate) fn current_directory(&self) -> Result<String> {
-        match self.backend.current_directory() {
-            Ok(mut v) => Ok(v.pop().unwrap_or_default()),
-            Err(e) => Err(e.into()),
+        Ok(self.device.current_directory()?.pop().unwrap_or_default())
+    }
+
+    /// Returns the names of the directories from the root down to the current
+    /// directory, empty at the root.
+    pub(crate) fn directory_path(&self) -> Result<Vec<String>> {
+        self.device.current_directory()
+    }
+
+    /// Changes to the directory at `path` below the root, as returned by
+    /// [`Controller::directory_path`].
+    pub(crate) fn set_directory_path(&self, path: &[String]) -> Result<()> {
+        self.device.change_directory(ChangeDirectory::ToRoot)?;
+        for dir in path {
+            self.device
+                .change_directory(ChangeDirectory::ToChild(dir))?;
         }
+        Ok(())
     }
 
-    pub(crate) fn change_directory(&self, dir: &str) -> Result<()> {
-        let d = {
-            if dir == "/" {
-                ChangeDirectory::ToRoot
-            } else if dir == ".." {
-                ChangeDirectory::ToParent
-            } else {
-                ChangeDirectory::ToChild(dir)
+    /// Changes the current directory along a `/` separated path, starting at
+    /// the root if the path begins with `/`. `.` stays, `..` goes up and `~`
+    /// goes to the root. If any step fails, the previous directory is
+    /// restored, so the change happens either completely or not at all. The
+    /// error of the step is returned even if restoring fails as well.
+    pub(crate) fn change_directory(&self, path: &str) -> Result<()> {
+        let steps = path
+            .starts_with('/')
+            .then_some(ChangeDirectory::ToRoot)
+            .into_iter()
+            .chain(path.split('/').filter_map(|dir| match dir {
+                "" | "." => None,
+                ".." => Some(ChangeDirectory::ToParent),
+                "~" => Some(ChangeDirectory::ToRoot),
+                dir => Some(ChangeDirectory::ToChild(dir)),
+            }));
+        let start = self.directory_path()?;
+        for step in steps {
+            if let Err(e) = self.device.change_directory(step) {
+                if let Err(rollback) = self.set_directory_path(&start) {
+                    warn!("cannot return to /{}: {rollback}", start.join("/"));
+                }
+                return Err(e);
             }
-        };
-        self.backend.change_directory(d).map_err(Into::into)
+        }
+        Ok(())
+    }
+
+    /// Changes to the child directory `name`, used when walking the tree.
+    pub(crate) fn enter_directory(&self, name: &str) -> Result<()> {
+        self.device.change_directory(ChangeDirectory::ToChild(name))
+    }
+
+    pub(crate) fn leave_directory(&self) -> Result<()> {
+        self.device.change_directory(ChangeDirectory::ToParent)
     }
 
     pub(crate) fn directory_content(&self) -> Result<DirectoryContent> {
-        self.backend.directory_content().map_err(Into::into)
+        self.device.directory_content()
+    }
+
+    /// Registers a pending request, sends it via `send` and waits for the
+    /// matching reply. After a timeout the request is kept as abandoned, so a
+    /// late reply is discarded instead of answering the next request of the
+    /// same kind. Without an explicit `timeout` the default of the request's
+    /// class is used.
+    fn request(
+        &self,
+        request: Request,
+        timeout: Option<Duration>,
+        send: impl FnOnce(&dyn Device),
+    ) -> Result<Reply> {
+        self.request_with_progress(request, timeout, send, |_, _| {})
+    }
+
+    fn request_with_progress(
+        &self,
+        request: Request,
+        timeout: Option<Duration>,
+        send: impl FnOnce(&dyn Device),
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Reply> {
+        let timeout = timeout.unwrap_or_else(|| self.timeouts.get().get(request.class()));
+        let deadline = Instant::now() + timeout;
+        let id = self.submit(request, false, send)?;
+        self.wait(id, Some(deadline), progress)
+    }
+
+    fn submit(
+        &self,
+        request: Request,
+        background: bool,
+        send: impl FnOnce(&dyn Device),
+    ) -> Result<u64> {
+        if !self.connection_state().connected {
+            match self.connect_timeout.get() {
+                Duration::ZERO => return Err(Error::Disconnected),
+                timeout => self.wait_connected(Some(timeout))?,
+            }
+        }
+        let id = {
+            let mut data = self.shared.0.lock().unwrap();
+            if !data.connected {
+                return Err(Error::Disconnected);
+            }
+            data.add(
+                request,
+                background,
+                self.timeouts.get().get(request.class()),
+            )?
+        };
+        send(&*self.device);
+        Ok(id)
+    }
+
+    fn wait(
+        &self,
+        id: u64,
+        deadline: Option<Instant>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<Reply> {
+        wait(&self.shared, id, deadline, progress)
     }
 
-    fn thread(
-        rx: Receiver<Command>,
-        receiver: Receiver<Event>,
-        shared: Arc<(Mutex<SharedData>, Condvar)>,
-    ) {
+    fn thread(rx: Receiver<Command>, receiver: Receiver<Event>, shared: Arc<Shared>) {
         let (mutex, cvar) = &*shared;
         loop {
             if let Ok(Command::Quit) = rx.try_recv() {
@@ -249,72 +553,82 @@ impl Controller {
             }
 
             if let Ok(event) = receiver.recv_timeout(Duration::from_millis(10)) {
+                let mut data = mutex.lock().unwrap();
+                let listener = match event {
+                    Event::Connected | Event::Disconnected => data.listener.clone(),
+                    _ => None,
+                };
                 match event {
-                    Event::Connected => info!("Connected"),
-                    Event::Disconnected => info!("Disconnected"),
+                    Event::Connected => {
+                        debug!("Connected");
+                        data.set_connected(true);
+                    }
+                    Event::Disconnected => {
+                        debug!("Disconnected");
+                        data.set_connected(false);
+                        data.fail_all(&Error::Disconnected);
+                    }
                     Event::InfoConnection(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.info_connection = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::InfoConnection, Reply::InfoConnection(res));
                     }
                     Event::InfoAbout(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.info_about = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::InfoAbout, Reply::InfoAbout(res));
                     }
                     Event::InfoMemory(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.info_memory = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::InfoMemory, Reply::InfoMemory(res));
                     }
                     Event::InfoSPIFlash(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.info_spiflash = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::InfoSPIFlash, Reply::InfoSPIFlash(res));
                     }
                     Event::WiFiScanResult(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.scan_result = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::WiFiScanResult, Reply::WiFiScanResult(res));
                     }
                     Event::WiFiNetworkList(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.network_list = Some(res);
-                        cvar.notify_one();
+                        data.complete(Request::WiFiNetworkList, Reply::WiFiNetworkList(res));
                     }
-                    Event::WiFiSetNetwork | Event::WiFiDeleteNetwork => {
-                        cvar.notify_one();
+                    Event::WiFiSetNetwork => data.complete(Request::WiFiSetNetwork, Reply::Done),
+                    Event::WiFiDeleteNetwork => {
+                        data.complete(Request::WiFiDeleteNetwork, Reply::Done);
                     }
-                    Event::FileSync(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        match res {
-                            FileSync::Started => info!("STARTED"),
-                            FileSync::Aborted => info!("ABORTED"),
-                            FileSync::Completed => {
-                                info!("COMPLETED");
-                                data.error = None;
-                                cvar.notify_one();
-                            }
+                    Event::FileSync(res) => match res {
+                        FileSync::Started => {
+                            info!("STARTED");
+                            data.start(Request::FileSync);
                         }
-                    }
-                    Event::TagSync(res) => {
-                        let mut data = mutex.lock().unwrap();
-                        match res {
-                            TagSync::Started => info!("STARTED"),
-                            TagSync::Aborted => info!("ABORTED"),
-                            TagSync::Step(n, t) => debug!("progress: {n}/{t}"),
-                            TagSync::Completed => {
-                                info!("COMPLETED");
-                                data.error = None;
-                                cvar.notify_one();
-                            }
+                        FileSync::Aborted => {
+                            info!("ABORTED");
+                            data.abort(Request::FileSync);
                         }
-                    }
-                    Event::Error(e) => {
-                        let mut data = mutex.lock().unwrap();
-                        data.error = Some(e.into());
-                        cvar.notify_one();
-                    }
+                        FileSync::Completed => {
+                            info!("COMPLETED");
+                            data.complete(Request::FileSync, Reply::Done);
+                        }
+                    },
+                    Event::TagSync(res) => match res {
+                        TagSync::Started => {
+                            debug!("STARTED");
+                            data.start(Request::TagSync);
+                        }
+                        TagSync::Aborted => {
+                            info!("ABORTED");
+                            data.abort(Request::TagSync);
+                        }
+                        TagSync::Step(n, t) => {
+                            debug!("progress: {n}/{t}");
+                            data.progress(Request::TagSync, n, t);
+                        }
+                        TagSync::Completed => {
+                            debug!("COMPLETED");
+                            data.complete(Request::TagSync, Reply::Done);
+                        }
+                    },
+                    Event::Error(e) => data.fail(e.into()),
+                }
+                cvar.notify_all();
+                let connected = data.connected;
+                drop(data);
+                if let Some(listener) = listener {
+                    listener(connected);
                 }
             }
         }
@@ -323,6 +637,299 @@ impl Controller {
     }
 }
 
+impl SharedData {
+    fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        self.changed = Some(Instant::now());
+    }
+
+    /// Registers a new pending request and returns its id. Abandoned requests
+    /// that have been waiting too long for their reply are dropped first. A
+    /// sync is refused while an abandoned one of the same kind may still run,
+    /// since the events of both could not be told apart. `timeout` is the
+    /// default timeout of the request's class.
+    fn add(&mut self, request: Request, background: bool, timeout: Duration) -> Result<u64> {
+        self.pending.retain(|p| !p.expired());
+        if request.is_sync()
+            && self
+                .pending
+                .iter()
+                .any(|p| p.request == request && p.abandoned.is_some())
+        {
+            return Err(Error::Busy(request.class()));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Pending {
+            id,
+            request,
+            reply: None,
+            progress: None,
+            started: false,
+            background,
+            abandoned: None,
+            sent: Instant::now(),
+            timeout,
+        });
+        Ok(id)
+    }
+
+    /// Returns the index of the oldest unanswered request of the given kind,
+    /// abandoned ones included. An overdue abandoned request is passed over
+    /// while a later one is still waited for, so that a lost reply does not
+    /// make every retry time out as well.
+    fn oldest(&self, request: Request) -> Option<usize> {
+        let unanswered = |p: &&Pending| p.request == request && p.reply.is_none();
+        let waited_for = self
+            .pending
+            .iter()
+            .filter(unanswered)
+            .any(|p| p.abandoned.is_none());
+        self.pending
+            .iter()
+            .position(|p| unanswered(&p) && !(waited_for && p.overdue()))
+    }
+
+    /// Hands the reply to the pending request at `index`. Nobody waits for an
+    /// abandoned request any more, so it is dropped together with the reply.
+    fn answer(&mut self, index: usize, reply: Result<Reply>) {
+        if self.pending[index].abandoned.is_some() {
+            let pending = self.pending.remove(index);
+            debug!("discard late reply to abandoned {:?}", pending.request);
+        } else {
+            self.pending[index].reply = Some(reply);
+        }
+    }
+
+    /// Hands the reply to the oldest pending request of the given kind.
+    fn complete(&mut self, request: Request, reply: Reply) {
+        if let Some(index) = self.oldest(request) {
+            self.answer(index, Ok(reply));
+        } else {
+            debug!("discard reply to {request:?} without pending request");
+        }
+    }
+
+    fn start(&mut self, request: Request) {
+        if let Some(index) = self.oldest(request) {
+            self.pending[index].started = true;
+        }
+    }
+
+    /// Fails the oldest pending request of the given kind with the progress
+    /// reached so far.
+    fn abort(&mut self, request: Request) {
+        if let Some(index) = self.oldest(request) {
+            let progress = self.pending[index].progress;
+            self.answer(index, Err(Error::Aborted(progress)));
+        }
+    }
+
+    fn progress(&mut self, request: Request, n: usize, t: usize) {
+        if let Some(index) = self.oldest(request) {
+            self.pending[index].progress = Some((n, t));
+        }
+    }
+
+    /// Hands the error to the oldest pending request. The backend handles
+    /// requests in order, so this is the one the error belongs to. A sync the
+    /// device has already started is skipped, unless nothing else is pending.
+    fn fail(&mut self, error: Error) {
+        let index = self
+            .pending
+            .iter()
+            .position(|p| p.reply.is_none() && !p.started)
+            .or_else(|| self.pending.iter().position(|p| p.reply.is_none()));
+        if let Some(index) = index {
+            self.answer(index, Err(error));
+        } else {
+            debug!("discard error without pending request: {error}");
+        }
+    }
+
+    /// Fails every pending request. Abandoned requests are dropped, their
+    /// replies will not come any more.
+    fn fail_all(&mut self, error: &Error) {
+        self.pending.retain(|p| p.abandoned.is_none());
+        for pending in self.pending.iter_mut().filter(|p| p.reply.is_none()) {
+            pending.reply = Some(Err(error.clone()));
+        }
+    }
+}
+
+/// Waits until the pending request `id` is answered, the `deadline` has
+/// passed or the wait is interrupted. Without a reply the request is kept as
+/// abandoned, so that a late reply or error still goes to it and not to the
+/// next request of the same kind. An interrupted background request stays a
+/// job instead. An interrupted sync fails with [`Error::Detached`], as the
+/// device cannot be told to stop it.
+fn wait(
+    shared: &Shared,
+    id: u64,
+    deadline: Option<Instant>,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<Reply> {
+    let (mutex, cvar) = shared;
+    let mut reported = None;
+    let mut interrupted = false;
+    let mut data = mutex.lock().unwrap();
+    loop {
+        let pending = data.pending.iter().find(|p| p.id == id).unwrap();
+        if pending.reply.is_some() {
+            break;
+        }
+        let current = pending.progress;
+        if mem::take(&mut data.interrupted) {
+            interrupted = true;
+            break;
+        }
+        if current != reported {
+            reported = current;
+            if let Some((n, t)) = reported {
+                drop(data);
+                progress(n, t);
+                data = mutex.lock().unwrap();
+                continue;
+            }
+        }
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            data = cvar.wait_timeout(data, deadline - now).unwrap().0;
+        } else {
+            data = cvar.wait(data).unwrap();
+        }
+    }
+    let index = data.pending.iter().position(|p| p.id == id).unwrap();
+    let pending = &mut data.pending[index];
+    if pending.reply.is_some() {
+        return data.pending.remove(index).reply.unwrap();
+    }
+    let error = if !interrupted {
+        Error::Timeout
+    } else if pending.request.is_sync() && !pending.background {
+        Error::Detached(reported)
+    } else {
+        Error::Interrupted(reported)
+    };
+    if !(interrupted && pending.background) {
+        pending.abandoned = Some(Instant::now());
+    }
+    Err(error)
+}
+
+impl Pending {
+    fn expired(&self) -> bool {
+        !self.started
+            && self
+                .abandoned
+                .is_some_and(|since| since.elapsed() >= ABANDONED_LIFETIME)
+    }
+
+    /// Returns whether the request was abandoned without being started and
+    /// has outlived the timeout of its class, so its reply is likely lost.
+    fn overdue(&self) -> bool {
+        !self.started && self.abandoned.is_some() && self.sent.elapsed() >= self.timeout
+    }
+    fn job(&self) -> Job {
+        Job {
+            id: self.id,
+            class: self.request.class(),
+            state: match &self.reply {
+                Some(Ok(_)) => JobState::Done,
+                Some(Err(e)) => JobState::Failed(e.clone()),
+                None if self.started => JobState::Running(self.progress),
+                None => JobState::Waiting,
+            },
+        }
+    }
+}
+
+impl Job {
+    pub(crate) fn finished(&self) -> bool {
+        matches!(self.state, JobState::Done | JobState::Failed(_))
+    }
+}
+
+impl Request {
+    fn is_sync(self) -> bool {
+        matches!(self, Self::FileSync | Self::TagSync)
+    }
+
+    fn class(self) -> RequestClass {
+        match self {
+            Self::InfoConnection | Self::InfoAbout | Self::InfoMemory | Self::InfoSPIFlash => {
+                RequestClass::Info
+            }
+            Self::WiFiScanResult
+            | Self::WiFiNetworkList
+            | Self::WiFiSetNetwork
+            | Self::WiFiDeleteNetwork => RequestClass::Network,
+            Self::FileSync => RequestClass::Files,
+            Self::TagSync => RequestClass::Tags,
+        }
+    }
+}
+
+impl RequestClass {
+    pub(crate) const ALL: [Self; 4] = [Self::Info, Self::Network, Self::Files, Self::Tags];
+}
+
+impl Timeouts {
+    pub(crate) fn get(&self, class: RequestClass) -> Duration {
+        match class {
+            RequestClass::Info => self.info,
+            RequestClass::Network => self.network,
+            RequestClass::Files => self.files,
+            RequestClass::Tags => self.tags,
+        }
+    }
+
+    pub(crate) fn set(&mut self, class: RequestClass, timeout: Duration) {
+        match class {
+            RequestClass::Info => self.info = timeout,
+            RequestClass::Network => self.network = timeout,
+            RequestClass::Files => self.files = timeout,
+            RequestClass::Tags => self.tags = timeout,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            info: Duration::from_secs(3),
+            network: Duration::from_secs(3),
+            files: Duration::from_secs(10),
+            tags: Duration::from_mins(10),
+        }
+    }
+}
+
+impl fmt::Display for RequestClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Network => write!(f, "network"),
+            Self::Files => write!(f, "files"),
+            Self::Tags => write!(f, "tags"),
+        }
+    }
+}
+
+impl FromStr for RequestClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|class| class.to_string() == s)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown request class: {s}")))
+    }
+}
+
 impl Drop for Controller {
     fn drop(&mut self) {
         self.sender.send(Command::Quit).unwrap();
@@ -330,13 +937,50 @@ impl Drop for Controller {
     }
 }
 
+impl Error {
+    /// Process exit code reported for this error in non-interactive mode.
+    pub(crate) fn exit_code(&self) -> u8 {
+        match self {
+            Self::Io(_) => 1,
+            Self::InvalidArgument(_) => 2,
+            Self::Timeout => 3,
+            Self::Disconnected => 4,
+            Self::Backend(_) | Self::Aborted(_) | Self::Busy(_) => 5,
+            Self::Interrupted(_) | Self::Detached(_) => 130,
+            Self::Script { error, .. } => error.exit_code(),
+        }
+    }
+
+    /// Returns whether the error is caused by Ctrl-C, also within a script.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        match self {
+            Self::Interrupted(_) | Self::Detached(_) => true,
+            Self::Script { error, .. } => error.is_interrupted(),
+            _ => false,
+        }
+    }
+}
+
 impl error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Timeout => write!(f, "timeout"),
+            Self::Disconnected => write!(f, "device not connected"),
             Self::Backend(e) => write!(f, "{e}"),
+            Self::Aborted(None) => write!(f, "aborted"),
+            Self::Aborted(Some((n, t))) => write!(f, "aborted after {n}/{t}"),
+            Self::Interrupted(None) => write!(f, "interrupted"),
+            Self::Interrupted(Some((n, t))) => write!(f, "interrupted after {n}/{t}"),
+            Self::Detached(None) => write!(f, "stopped waiting, the device goes on syncing"),
+            Self::Detached(Some((n, t))) => {
+                write!(f, "stopped waiting at {n}/{t}, the device goes on syncing")
+            }
+            Self::Busy(class) => write!(f, "the device is still syncing {class}"),
+            Self::InvalidArgument(msg) => write!(f, "{msg}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Script { path, line, error } => write!(f, "{path}:{line}: {error}"),
         }
     }
 }
@@ -346,3 +990,573 @@ impl From<backend::Error> for Error {
         Self::Backend(value)
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Self::Io(Arc::new(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::thread;
+
+    use super::*;
+
+    fn shared() -> Arc<Shared> {
+        let data = SharedData {
+            connected: true,
+            ..SharedData::default()
+        };
+        Arc::new((Mutex::new(data), Condvar::new()))
+    }
+
+    fn add(shared: &Shared, request: Request) -> u64 {
+        let timeout = Timeouts::default().get(request.class());
+        shared
+            .0
+            .lock()
+            .unwrap()
+            .add(request, false, timeout)
+            .unwrap()
+    }
+
+    /// Waits for `id` with a deadline that has already passed.
+    fn poll(shared: &Shared, id: u64) -> Result<Reply> {
+        wait(shared, id, Some(Instant::now()), |_, _| {})
+    }
+
+    /// Deadline of a wait that is meant to end earlier, so that a broken test
+    /// fails instead of hanging.
+    fn soon() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    /// Interrupts the wait that is about to start, as Ctrl-C would. The flag
+    /// is kept until a wait sees it, so the wait may also start later.
+    fn interrupt_soon(shared: &Arc<Shared>) -> JoinHandle<()> {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            shared.0.lock().unwrap().interrupted = true;
+            shared.1.notify_all();
+        })
+    }
+
+    fn list(words: &[&str]) -> Reply {
+        Reply::WiFiNetworkList(words.iter().map(|&w| w.to_owned()).collect())
+    }
+
+    fn networks(reply: Result<Reply>) -> Vec<String> {
+        match reply {
+            Ok(Reply::WiFiNetworkList(list)) => list,
+            _ => panic!("no network list"),
+        }
+    }
+
+    #[test]
+    fn late_reply_goes_to_abandoned_request() {
+        let shared = shared();
+        let first = add(&shared, Request::WiFiNetworkList);
+        assert!(matches!(poll(&shared, first), Err(Error::Timeout)));
+        let second = add(&shared, Request::WiFiNetworkList);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.complete(Request::WiFiNetworkList, list(&["late"]));
+            data.complete(Request::WiFiNetworkList, list(&["mine"]));
+        }
+        assert_eq!(networks(poll(&shared, second)), ["mine"]);
+        assert!(shared.0.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn lost_reply_does_not_break_retries() {
+        let shared = shared();
+        let timeout = Duration::from_millis(20);
+        let add = |shared: &Shared| {
+            let mut data = shared.0.lock().unwrap();
+            data.add(Request::WiFiNetworkList, false, timeout).unwrap()
+        };
+        let lost = add(&shared);
+        let deadline = Instant::now() + timeout;
+        let result = wait(&shared, lost, Some(deadline), |_, _| {});
+        assert!(matches!(result, Err(Error::Timeout)));
+        for retry in ["first", "second", "third"] {
+            let id = add(&shared);
+            shared
+                .0
+                .lock()
+                .unwrap()
+                .complete(Request::WiFiNetworkList, list(&[retry]));
+            assert_eq!(networks(poll(&shared, id)), [retry]);
+        }
+        shared
+            .0
+            .lock()
+            .unwrap()
+            .complete(Request::WiFiNetworkList, list(&["lost"]));
+        assert!(shared.0.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn late_error_goes_to_abandoned_request() {
+        let shared = shared();
+        let first = add(&shared, Request::InfoAbout);
+        assert!(matches!(poll(&shared, first), Err(Error::Timeout)));
+        let second = add(&shared, Request::WiFiNetworkList);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.fail(Error::InvalidArgument("late".into()));
+            data.complete(Request::WiFiNetworkList, list(&["mine"]));
+        }
+        assert_eq!(networks(poll(&shared, second)), ["mine"]);
+    }
+
+    #[test]
+    fn interrupted_request_is_abandoned() {
+        let shared = shared();
+        let first = add(&shared, Request::WiFiNetworkList);
+        let interrupter = interrupt_soon(&shared);
+        let result = wait(&shared, first, Some(soon()), |_, _| {});
+        interrupter.join().unwrap();
+        assert!(matches!(result, Err(Error::Interrupted(None))));
+        let second = add(&shared, Request::WiFiNetworkList);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.complete(Request::WiFiNetworkList, list(&["late"]));
+            data.complete(Request::WiFiNetworkList, list(&["mine"]));
+        }
+        assert_eq!(networks(poll(&shared, second)), ["mine"]);
+    }
+
+    #[test]
+    fn disconnect_drops_abandoned_requests() {
+        let shared = shared();
+        let first = add(&shared, Request::WiFiNetworkList);
+        assert!(matches!(poll(&shared, first), Err(Error::Timeout)));
+        let second = add(&shared, Request::WiFiNetworkList);
+        shared.0.lock().unwrap().fail_all(&Error::Disconnected);
+        assert!(matches!(poll(&shared, second), Err(Error::Disconnected)));
+        assert!(shared.0.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn expired_abandoned_request_is_dropped() {
+        let shared = shared();
+        let first = add(&shared, Request::WiFiNetworkList);
+        assert!(matches!(poll(&shared, first), Err(Error::Timeout)));
+        shared.0.lock().unwrap().pending[0].abandoned =
+            Instant::now().checked_sub(ABANDONED_LIFETIME);
+        let second = add(&shared, Request::WiFiNetworkList);
+        shared
+            .0
+            .lock()
+            .unwrap()
+            .complete(Request::WiFiNetworkList, list(&["mine"]));
+        assert_eq!(networks(poll(&shared, second)), ["mine"]);
+    }
+
+    #[test]
+    fn interrupted_sync_blocks_the_next_one() {
+        let shared = shared();
+        let first = add(&shared, Request::TagSync);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.start(Request::TagSync);
+            data.progress(Request::TagSync, 1, 4);
+        }
+        let interrupter = interrupt_soon(&shared);
+        let result = wait(&shared, first, Some(soon()), |_, _| {});
+        interrupter.join().unwrap();
+        assert!(matches!(result, Err(Error::Detached(Some((1, 4))))));
+
+        let mut data = shared.0.lock().unwrap();
+        assert!(matches!(
+            data.add(Request::TagSync, false, Duration::ZERO),
+            Err(Error::Busy(RequestClass::Tags))
+        ));
+        assert!(data.add(Request::FileSync, true, Duration::ZERO).is_ok());
+        data.progress(Request::TagSync, 4, 4);
+        data.complete(Request::TagSync, Reply::Done);
+        assert!(data.add(Request::TagSync, false, Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn replies_go_to_the_oldest_request_of_their_kind() {
+        let shared = shared();
+        let first = add(&shared, Request::WiFiNetworkList);
+        let other = add(&shared, Request::WiFiSetNetwork);
+        let second = add(&shared, Request::WiFiNetworkList);
+        {
+            let mut data = shared.0.lock().unwrap();
+            assert_eq!(data.oldest(Request::WiFiNetworkList), Some(0));
+            data.complete(Request::WiFiNetworkList, list(&["first"]));
+            assert_eq!(data.oldest(Request::WiFiNetworkList), Some(2));
+            data.complete(Request::WiFiSetNetwork, Reply::Done);
+            data.complete(Request::WiFiNetworkList, list(&["second"]));
+            assert_eq!(data.oldest(Request::WiFiNetworkList), None);
+            data.complete(Request::WiFiNetworkList, list(&["unexpected"]));
+        }
+        assert_eq!(networks(poll(&shared, second)), ["second"]);
+        assert!(matches!(poll(&shared, other), Ok(Reply::Done)));
+        assert_eq!(networks(poll(&shared, first)), ["first"]);
+        assert!(shared.0.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn error_before_reply_fails_the_request() {
+        let shared = shared();
+        let id = add(&shared, Request::WiFiNetworkList);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.fail(Error::InvalidArgument("failed".into()));
+            data.complete(Request::WiFiNetworkList, list(&["late"]));
+        }
+        assert!(matches!(poll(&shared, id), Err(Error::InvalidArgument(_))));
+        assert!(shared.0.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn error_skips_a_started_sync() {
+        let shared = shared();
+        let sync = add(&shared, Request::TagSync);
+        shared.0.lock().unwrap().start(Request::TagSync);
+        let id = add(&shared, Request::WiFiNetworkList);
+        shared.0.lock().unwrap().fail(Error::Disconnected);
+        assert!(matches!(poll(&shared, id), Err(Error::Disconnected)));
+        shared.0.lock().unwrap().fail(Error::Disconnected);
+        assert!(matches!(poll(&shared, sync), Err(Error::Disconnected)));
+    }
+
+    #[test]
+    fn abort_keeps_the_progress() {
+        let shared = shared();
+        let id = add(&shared, Request::TagSync);
+        {
+            let mut data = shared.0.lock().unwrap();
+            data.start(Request::TagSync);
+            data.progress(Request::TagSync, 2, 5);
+            data.abort(Request::TagSync);
+        }
+        assert!(matches!(
+            poll(&shared, id),
+            Err(Error::Aborted(Some((2, 5))))
+        ));
+    }
+
+    #[test]
+    fn wait_reports_progress_until_the_reply() {
+        let shared = shared();
+        let id = add(&shared, Request::TagSync);
+        let device = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for n in 1..=3 {
+                    thread::sleep(Duration::from_millis(10));
+                    shared.0.lock().unwrap().progress(Request::TagSync, n, 3);
+                    shared.1.notify_all();
+                }
+                thread::sleep(Duration::from_millis(50));
+                shared
+                    .0
+                    .lock()
+                    .unwrap()
+                    .complete(Request::TagSync, Reply::Done);
+                shared.1.notify_all();
+            })
+        };
+        let mut reported = Vec::new();
+        let result = wait(&shared, id, Some(soon()), |n, t| reported.push((n, t)));
+        device.join().unwrap();
+        assert!(matches!(result, Ok(Reply::Done)));
+        assert_eq!(reported.last(), Some(&(3, 3)));
+        assert!(reported.is_sorted());
+    }
+
+    #[test]
+    fn wait_times_out_at_the_deadline() {
+        let shared = shared();
+        let id = add(&shared, Request::InfoAbout);
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(30);
+        let result = wait(&shared, id, Some(deadline), |_, _| {});
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn disconnect_fails_the_waiting_request() {
+        let shared = shared();
+        let id = add(&shared, Request::WiFiNetworkList);
+        let device = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                let mut data = shared.0.lock().unwrap();
+                data.set_connected(false);
+                data.fail_all(&Error::Disconnected);
+                shared.1.notify_all();
+            })
+        };
+        let result = wait(&shared, id, Some(soon()), |_, _| {});
+        device.join().unwrap();
+        assert!(matches!(result, Err(Error::Disconnected)));
+        assert!(!shared.0.lock().unwrap().connected);
+    }
+
+    #[test]
+    fn interrupted_background_request_stays_a_job() {
+        let shared = shared();
+        let id = shared
+            .0
+            .lock()
+            .unwrap()
+            .add(Request::FileSync, true, Duration::ZERO)
+            .unwrap();
+        let interrupter = interrupt_soon(&shared);
+        let result = wait(&shared, id, Some(soon()), |_, _| {});
+        interrupter.join().unwrap();
+        assert!(matches!(result, Err(Error::Interrupted(None))));
+        let data = shared.0.lock().unwrap();
+        assert!(data.pending[0].abandoned.is_none());
+        assert!(matches!(data.pending[0].job().state, JobState::Waiting));
+    }
+
+    /// Device answering every request with the next list of scripted events.
+    /// The events are sent at once, so the control thread handles them after
+    /// the request has been registered.
+    struct Scripted {
+        events: Sender<Event>,
+        receiver: RefCell<Option<Receiver<Event>>>,
+        answers: RefCell<VecDeque<Vec<Event>>>,
+    }
+
+    impl Scripted {
+        /// Returns a controller for a connected device that answers the
+        /// requests with `answers`, and with nothing once they are used up.
+        fn controller(answers: Vec<Vec<Event>>) -> Controller {
+            let (events, receiver) = mpsc::channel();
+            events.send(Event::Connected).unwrap();
+            let device = Self {
+                events,
+                receiver: RefCell::new(Some(receiver)),
+                answers: RefCell::new(answers.into()),
+            };
+            let ctrl = Controller::new(Box::new(device));
+            ctrl.wait_connected(Some(Duration::from_secs(5))).unwrap();
+            ctrl
+        }
+
+        fn answer(&self) {
+            let answer = self.answers.borrow_mut().pop_front().unwrap_or_default();
+            for event in answer {
+                self.events.send(event).unwrap();
+            }
+        }
+    }
+
+    impl Device for Scripted {
+        fn receiver(&self) -> Option<Receiver<Event>> {
+            self.receiver.borrow_mut().take()
+        }
+
+        fn get_access_point_mode(&self) -> bool {
+            true
+        }
+
+        fn set_access_point_mode(&self, _auto: bool) {}
+
+        fn get_info_connection(&self) {
+            self.answer();
+        }
+
+        fn get_info_about(&self) {
+            self.answer();
+        }
+
+        fn get_info_memory(&self) {
+            self.answer();
+        }
+
+        fn get_info_spiflash(&self) {
+            self.answer();
+        }
+
+        fn get_wifi_scan_result(&self) {
+            self.answer();
+        }
+
+        fn get_wifi_network_list(&self) {
+            self.answer();
+        }
+
+        fn set_wifi_network(&self, _ssid: String, _key: String) {
+            self.answer();
+        }
+
+        fn delete_wifi_network(&self, _ssid: String) {
+            self.answer();
+        }
+
+        fn sync_files(&self) {
+            self.answer();
+        }
+
+        fn sync_tags(&self) {
+            self.answer();
+        }
+
+        fn current_directory(&self) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+
+        fn change_directory(&self, _dir: ChangeDirectory<'_>) -> Result<()> {
+            Ok(())
+        }
+
+        fn directory_content(&self) -> Result<DirectoryContent> {
+            Ok(DirectoryContent {
+                dirs: Vec::new(),
+                cover: None,
+                tracks: Vec::new(),
+            })
+        }
+    }
+
+    fn about(version: &str) -> Event {
+        Event::InfoAbout(About {
+            project: "audio-player".into(),
+            version: version.into(),
+            esp_idf: "v5.1".into(),
+        })
+    }
+
+    fn version(about: Result<About>) -> String {
+        about.map(|about| about.version).unwrap()
+    }
+
+    /// Events of a tag sync that the device has started.
+    fn tags(steps: Vec<Event>) -> Vec<Event> {
+        let mut events = vec![Event::TagSync(TagSync::Started)];
+        events.extend(steps);
+        events
+    }
+
+    #[test]
+    fn controller_returns_replies() {
+        let ctrl = Scripted::controller(vec![
+            vec![about("1.0")],
+            vec![Event::WiFiSetNetwork],
+            vec![Event::WiFiNetworkList(vec!["Home".into(), "Café".into()])],
+            tags(vec![
+                Event::TagSync(TagSync::Step(1, 2)),
+                Event::TagSync(TagSync::Step(2, 2)),
+                Event::TagSync(TagSync::Completed),
+            ]),
+        ]);
+        assert_eq!(version(ctrl.get_info_about(None)), "1.0");
+        ctrl.set_wifi_network("Café".into(), "secret".into(), None)
+            .unwrap();
+        assert_eq!(ctrl.get_wifi_network_list(None).unwrap(), ["Home", "Café"]);
+        ctrl.sync_tags(None, |_, _| {}).unwrap();
+        assert!(ctrl.jobs().is_empty());
+    }
+
+    #[test]
+    fn controller_returns_device_errors() {
+        let ctrl = Scripted::controller(vec![
+            vec![Event::Error(backend::Error)],
+            vec![Event::Error(backend::Error), Event::WiFiSetNetwork],
+            vec![about("1.0")],
+        ]);
+        assert!(matches!(ctrl.get_info_about(None), Err(Error::Backend(_))));
+        assert!(matches!(
+            ctrl.set_wifi_network("Home".into(), "secret".into(), None),
+            Err(Error::Backend(_))
+        ));
+        assert_eq!(version(ctrl.get_info_about(None)), "1.0");
+    }
+
+    #[test]
+    fn controller_times_out_and_recovers() {
+        let ctrl = Scripted::controller(vec![
+            vec![],
+            vec![about("late"), about("mine")],
+            vec![],
+            vec![about("retry")],
+        ]);
+        let timeout = Some(Duration::from_millis(50));
+        assert!(matches!(ctrl.get_info_about(timeout), Err(Error::Timeout)));
+        assert_eq!(version(ctrl.get_info_about(None)), "mine");
+
+        ctrl.set_timeout(RequestClass::Info, Duration::from_millis(50));
+        assert!(matches!(ctrl.get_info_about(None), Err(Error::Timeout)));
+        assert_eq!(version(ctrl.get_info_about(None)), "retry");
+    }
+
+    #[test]
+    fn controller_fails_requests_on_disconnect() {
+        let ctrl = Scripted::controller(vec![tags(vec![
+            Event::TagSync(TagSync::Step(1, 4)),
+            Event::Disconnected,
+        ])]);
+        assert!(matches!(
+            ctrl.sync_tags(None, |_, _| {}),
+            Err(Error::Disconnected)
+        ));
+        assert!(!ctrl.connection_state().connected);
+        assert!(matches!(
+            ctrl.get_info_about(None),
+            Err(Error::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn controller_reports_aborted_sync() {
+        let ctrl = Scripted::controller(vec![tags(vec![
+            Event::TagSync(TagSync::Step(1, 3)),
+            Event::TagSync(TagSync::Aborted),
+        ])]);
+        assert!(matches!(
+            ctrl.sync_tags(None, |_, _| {}),
+            Err(Error::Aborted(Some((1, 3))))
+        ));
+    }
+
+    #[test]
+    fn controller_tracks_background_sync() {
+        let ctrl = Scripted::controller(vec![vec![
+            Event::FileSync(FileSync::Started),
+            Event::FileSync(FileSync::Completed),
+        ]]);
+        let job = ctrl.start_sync_files().unwrap();
+        ctrl.wait_job(job.id, |_, _| {}).unwrap();
+        assert!(ctrl.jobs().is_empty());
+    }
+
+    #[cfg(feature = "simulator")]
+    #[test]
+    fn late_reply_from_simulated_device_is_discarded() {
+        use crate::simulator::{Setup, Simulator};
+
+        let setup: Setup = toml::from_str("faults = [\"late\"]").unwrap();
+        let ctrl = Controller::new(Box::new(Simulator::new(setup)));
+        ctrl.wait_connected(Some(Duration::from_secs(1))).unwrap();
+        assert!(matches!(
+            ctrl.get_wifi_network_list(Some(Duration::from_millis(100))),
+            Err(Error::Timeout)
+        ));
+        ctrl.set_wifi_network(
+            "Cafe".into(),
+            "secret".into(),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        let networks = ctrl
+            .get_wifi_network_list(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(networks, ["Home", "Cafe"]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::mpsc::Receiver;
+
+use backend::{Backend, ChangeDirectory, DirectoryContent, Event};
+
+use crate::control::Result;
+
+/// The device as seen by the controller. Requests are sent with the methods
+/// below and answered by [`Event`]s on the receiver, except for the file
+/// system, which is answered at once from the synced file list.
+pub(crate) trait Device {
+    /// Returns the receiver of the device's events, only on the first call.
+    fn receiver(&self) -> Option<Receiver<Event>>;
+    fn get_access_point_mode(&self) -> bool;
+    fn set_access_point_mode(&self, auto: bool);
+    fn get_info_connection(&self);
+    fn get_info_about(&self);
+    fn get_info_memory(&self);
+    fn get_info_spiflash(&self);
+    fn get_wifi_scan_result(&self);
+    fn get_wifi_network_list(&self);
+    fn set_wifi_network(&self, ssid: String, key: String);
+    fn delete_wifi_network(&self, ssid: String);
+    fn sync_files(&self);
+    fn sync_tags(&self);
+    fn current_directory(&self) -> Result<Vec<String>>;
+    fn change_directory(&self, dir: ChangeDirectory<'_>) -> Result<()>;
+    fn directory_content(&self) -> Result<DirectoryContent>;
+}
+
+impl Device for Backend {
+    fn receiver(&self) -> Option<Receiver<Event>> {
+        Backend::receiver(self)
+    }
+
+    fn get_access_point_mode(&self) -> bool {
+        Backend::get_access_point_mode(self)
+    }
+
+    fn set_access_point_mode(&self, auto: bool) {
+        Backend::set_access_point_mode(self, auto);
+    }
+
+    fn get_info_connection(&self) {
+        Backend::get_info_connection(self);
+    }
+
+    fn get_info_about(&self) {
+        Backend::get_info_about(self);
+    }
+
+    fn get_info_memory(&self) {
+        Backend::get_info_memory(self);
+    }
+
+    fn get_info_spiflash(&self) {
+        Backend::get_info_spiflash(self);
+    }
+
+    fn get_wifi_scan_result(&self) {
+        Backend::get_wifi_scan_result(self);
+    }
+
+    fn get_wifi_network_list(&self) {
+        Backend::get_wifi_network_list(self);
+    }
+
+    fn set_wifi_network(&self, ssid: String, key: String) {
+        Backend::set_wifi_network(self, ssid, key);
+    }
+
+    fn delete_wifi_network(&self, ssid: String) {
+        Backend::delete_wifi_network(self, ssid);
+    }
+
+    fn sync_files(&self) {
+        Backend::sync_files(self);
+    }
+
+    fn sync_tags(&self) {
+        Backend::sync_tags(self);
+    }
+
+    fn current_directory(&self) -> Result<Vec<String>> {
+        Backend::current_directory(self).map_err(Into::into)
+    }
+
+    fn change_directory(&self, dir: ChangeDirectory<'_>) -> Result<()> {
+        Backend::change_directory(self, dir).map_err(Into::into)
+    }
+
+    fn directory_content(&self) -> Result<DirectoryContent> {
+        Backend::directory_content(self).map_err(Into::into)
+    }
+}
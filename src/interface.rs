@@ -1,92 +1,83 @@
-#![allow(clippy::print_stdout)]
-use std::cmp;
+use std::io;
+use std::io::{IsTerminal as _, Write as _};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use colored::Colorize as _;
 use smart_repl::{Args, Command, Group, Parameter, Repl};
 
-use crate::control::Controller;
+use crate::command;
+use crate::context::Context;
+use crate::control::Result;
 
 pub(crate) struct Cli<'a> {
-    repl: Repl<'a, Controller>,
+    repl: Repl<'a, Context>,
 }
 
 impl<'a> Cli<'a> {
-    pub(crate) fn new(ctrl: &'a Controller) -> Self {
+    pub(crate) fn new(ctx: &'a Context) -> Self {
+        let prompt = ctx.prompt().to_owned();
+        ctx.ctrl
+            .on_connection_change(move |connected| status(&prompt, connected));
         let repl = Repl::builder()
-            .with_context(ctrl)
-            .with_prompt(">> ")
+            .with_context(ctx)
+            .with_prompt(ctx.prompt())
             .with_help()
-            .with_command(Command::new("version", version).with_help("Show version information."))
             .with_command(
-                Command::new("connection", connection).with_help("Show connection status."),
+                Command::new("version", |ctx, args| execute(ctx, args, "version", &[]))
+                    .with_help("Show version information."),
             )
-            .with_group(
-                Group::new("ap")
-                    .with_help("Handle connection to the device's access point.")
-                    .with_command(
-                        Command::new("auto", ap_auto)
-                            .with_help("Switch automatic access point connection on/off.")
-                            .with_optional_parameter(Parameter::bool("value", "on", "off")),
-                    ),
-            )
-            .with_group(
-                Group::new("info")
-                    .with_help("Show device information.")
-                    .with_command(
-                        Command::new("about", info_about)
-                            .with_help("Show device version information."),
-                    )
-                    .with_command(
-                        Command::new("memory", info_memory)
-                            .with_help("Show device memory information."),
-                    )
-                    .with_command(
-                        Command::new("flash", info_flash)
-                            .with_help("Show device flash information."),
-                    ),
+            .with_command(
+                Command::new("format", |ctx, args| {
+                    execute(ctx, args, "format", &["format"]);
+                })
+                .with_help("Show or set the output format (text or json).")
+                .with_optional_parameter(Parameter::string("format")),
             )
-            .with_group(
-                Group::new("network")
-                    .with_help("Configure the networks to which the device should connect.")
-                    .with_command(
-                        Command::new("scan", network_scan)
-                            .with_help("Display the list of networks scanned by device."),
-                    )
-                    .with_command(
-                        Command::new("list", network_list)
-                            .with_help("Display the list of configured networks."),
-                    )
-                    .with_command(
-                        Command::new("add", network_add)
-                            .with_help("Add a network to the list or edit an existing one.")
-                            .with_parameter(Parameter::string("ssid"))
-                            .with_parameter(Parameter::string("key")),
-                    )
-                    .with_command(
-                        Command::new("remove", network_remove)
-                            .with_help("Remove a network from the list.")
-                            .with_parameter(Parameter::string("ssid")),
-                    ),
+            .with_group(config_group())
+            .with_group(log_group())
+            .with_command(
+                Command::new("timeout", |ctx, args| {
+                    execute(ctx, args, "timeout", &["class", "value"]);
+                })
+                .with_help("Show or set the default timeout of a request class.")
+                .with_optional_parameter(Parameter::string("class"))
+                .with_optional_parameter(Parameter::string("value")),
             )
-            .with_group(
-                Group::new("sync")
-                    .with_help("Sync data from device.")
-                    .with_command(Command::new("files", sync_files).with_help("Sync file list."))
-                    .with_command(
-                        Command::new("tags", sync_tags)
-                            .with_help("Sync tags of current and child directory."),
-                    ),
+            .with_command(with_timeout(
+                Command::new("wait", |ctx, args| execute(ctx, args, "wait", TIMEOUT))
+                    .with_help("Wait until the device is connected."),
+            ))
+            .with_command(with_timeout(
+                Command::new("connection", |ctx, args| {
+                    execute(ctx, args, "connection", TIMEOUT);
+                })
+                .with_help("Show connection status."),
+            ))
+            .with_group(ap_group())
+            .with_group(info_group())
+            .with_group(network_group())
+            .with_group(sync_group())
+            .with_command(
+                // a command with an optional action, so that 'jobs' alone lists the jobs
+                Command::new("jobs", |ctx, args| {
+                    execute(ctx, args, "jobs", &["action", "id"]);
+                })
+                .with_help(
+                    "List background syncs, 'jobs wait [id]' waits for one or all of them, \
+                     'jobs cancel <id>' stops tracking one. The device cannot be stopped and \
+                     finishes it anyway.",
+                )
+                .with_optional_parameter(Parameter::string("action"))
+                .with_optional_parameter(Parameter::string("id")),
             )
-            .with_group(
-                Group::new("fs")
-                    .with_help("Access file system.")
-                    .with_command(Command::new("pwd", fs_pwd).with_help("Print current directory."))
-                    .with_command(
-                        Command::new("cd", fs_cd)
-                            .with_help("Change directory.")
-                            .with_parameter(Parameter::string("dir")),
-                    )
-                    .with_command(Command::new("ls", fs_ls).with_help("Print directory content.")),
+            .with_group(fs_group())
+            .with_command(
+                Command::new("source", |ctx, args| {
+                    execute(ctx, args, "source", &["file", "errors"]);
+                })
+                .with_help("Run the commands of a script file, past errors with '--keep-going'.")
+                .with_parameter(Parameter::string("file"))
+                .with_optional_parameter(Parameter::string("errors")),
             )
             .build();
 
@@ -98,201 +89,248 @@ impl<'a> Cli<'a> {
     }
 }
 
-fn version(_: Option<&Controller>, _: Args) {
-    let versions = vec![
-        (
-            env!("CARGO_PKG_NAME").to_owned() + ":",
-            env!("VERSION").to_owned(),
-        ),
-        (
-            smart_repl::NAME.to_owned() + ":",
-            smart_repl::VERSION.to_owned(),
-        ),
-        (
-            Controller::backend_name().to_owned() + ":",
-            Controller::backend_version().to_owned(),
-        ),
-    ];
-    let max = versions.iter().fold(0, |m, (n, _)| cmp::max(m, n.len()));
-    for (n, v) in &versions {
-        println!("{n:max$} {v}");
-    }
-}
+/// Parameters of a command taking a timeout, either `<duration>` or
+/// `--timeout <duration>`.
+const TIMEOUT: &[&str] = &["timeout", "duration"];
 
-fn ap_auto(ctrl: Option<&Controller>, args: Args) {
-    let ctrl = ctrl.unwrap();
-    if let Some(val) = args.get_bool("value").unwrap() {
-        ctrl.set_access_point_mode(val);
-    } else if ctrl.get_access_point_mode() {
-        println!("on");
-    } else {
-        println!("off");
-    }
-}
-
-fn connection(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_info_connection();
-    match result {
-        Ok(info) => {
-            println!("Mode: {}", info.mode);
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn with_timeout(command: Command<Context>) -> Command<Context> {
+    command
+        .with_optional_parameter(Parameter::string("timeout"))
+        .with_optional_parameter(Parameter::string("duration"))
 }
 
-fn info_about(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_info_about();
-    match result {
-        Ok(info) => {
-            println!("Project:   {}", info.project);
-            println!("Version:   {}", info.version);
-            println!("ESP-IDF:   {}", info.esp_idf);
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn config_group() -> Group<Context> {
+    Group::new("config")
+        .with_help("Show or change the configuration file.")
+        .with_command(
+            Command::new("show", |ctx, args| execute(ctx, args, "config show", &[]))
+                .with_help("Show the settings in effect."),
+        )
+        .with_command(
+            Command::new("set", |ctx, args| {
+                execute(ctx, args, "config set", &["key", "value"]);
+            })
+            .with_help(
+                "Change a setting: format, prompt, color, ap_auto, timeouts.<class>, \
+                 log.level, log.ignore, log.file or log.file_level.",
+            )
+            .with_parameter(Parameter::string("key"))
+            .with_parameter(Parameter::string("value")),
+        )
 }
 
-fn info_memory(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_info_memory();
-    match result {
-        Ok(info) => {
-            println!("heap");
-            println!(
-                "   total:        {:3} KiB",
-                info.heap.allocated / 1024 + info.heap.free / 1024
-            );
-            println!("   allocated:    {:3} KiB", info.heap.allocated / 1024);
-            println!("   free:         {:3} KiB", info.heap.free / 1024);
-            println!("   minimum free: {:3} KiB", info.heap.minimum_free / 1024);
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn log_group() -> Group<Context> {
+    Group::new("log")
+        .with_help("Control logging.")
+        .with_command(
+            Command::new("show", |ctx, args| execute(ctx, args, "log show", &[]))
+                .with_help("Show log level, log file and modules."),
+        )
+        .with_command(
+            Command::new("level", |ctx, args| {
+                execute(ctx, args, "log level", &["level"]);
+            })
+            .with_help("Show or set the log level (off, error, warn, info, debug, trace).")
+            .with_optional_parameter(Parameter::string("level")),
+        )
+        .with_command(
+            Command::new("file", |ctx, args| {
+                execute(ctx, args, "log file", &["path", "level"]);
+            })
+            .with_help("Also write the log to a rotating file, 'off' to stop.")
+            .with_parameter(Parameter::string("path"))
+            .with_optional_parameter(Parameter::string("level")),
+        )
+        .with_command(
+            Command::new("module", |ctx, args| {
+                execute(ctx, args, "log module", &["module", "value"]);
+            })
+            .with_help("Switch logging of a module on/off.")
+            .with_parameter(Parameter::string("module"))
+            .with_parameter(Parameter::string("value")),
+        )
 }
 
-fn info_flash(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_info_spiflash();
-    match result {
-        Ok(info) => {
-            println!("files");
-            for f in info.files {
-                println!("   {} {:6} {}", f.md5, f.size, f.name);
-            }
-            println!("total: {:3} KiB", info.total / 1024);
-            println!("free:  {:3} KiB", info.free / 1024);
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn ap_group() -> Group<Context> {
+    Group::new("ap")
+        .with_help("Handle connection to the device's access point.")
+        .with_command(
+            Command::new("auto", |ctx, args| {
+                execute(ctx, args, "ap auto", &["value"]);
+            })
+            .with_help("Switch automatic access point connection on/off.")
+            .with_optional_parameter(Parameter::string("value")),
+        )
 }
 
-fn network_scan(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_wifi_scan_result();
-    match result {
-        Ok(list) => {
-            for network in list {
-                //println!("{} {}", '\u{1f6dc}', network.ssid);
-                match network.rssi {
-                    3 => println!("\u{1f7e2} {}", network.ssid),
-                    2 => println!("\u{1f7e1} {}", network.ssid),
-                    1 => println!("\u{1f534} {}", network.ssid),
-                    _ => println!("? {}", network.ssid),
-                }
-            }
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn info_group() -> Group<Context> {
+    Group::new("info")
+        .with_help("Show device information, with a timeout as '5s' or '--timeout 5s'.")
+        .with_command(with_timeout(
+            Command::new("about", |ctx, args| {
+                execute(ctx, args, "info about", TIMEOUT);
+            })
+            .with_help("Show device version information."),
+        ))
+        .with_command(with_timeout(
+            Command::new("memory", |ctx, args| {
+                execute(ctx, args, "info memory", TIMEOUT);
+            })
+            .with_help("Show device memory information."),
+        ))
+        .with_command(with_timeout(
+            Command::new("flash", |ctx, args| {
+                execute(ctx, args, "info flash", TIMEOUT);
+            })
+            .with_help("Show device flash information."),
+        ))
 }
 
-fn network_list(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.get_wifi_network_list();
-    match result {
-        Ok(list) => {
-            for network in list {
-                println!("{network}");
-            }
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn network_group() -> Group<Context> {
+    Group::new("network")
+        .with_help(
+            "Configure the networks to which the device should connect, with a timeout as \
+             '5s' or '--timeout 5s'.",
+        )
+        .with_command(with_timeout(
+            Command::new("scan", |ctx, args| {
+                execute(ctx, args, "network scan", TIMEOUT);
+            })
+            .with_help("Display the list of networks scanned by device."),
+        ))
+        .with_command(with_timeout(
+            Command::new("list", |ctx, args| {
+                execute(ctx, args, "network list", TIMEOUT);
+            })
+            .with_help("Display the list of configured networks."),
+        ))
+        .with_command(with_timeout(
+            Command::new("add", |ctx, args| {
+                execute(
+                    ctx,
+                    args,
+                    "network add",
+                    &["ssid", "key", "timeout", "duration"],
+                );
+            })
+            .with_help("Add a network to the list or edit an existing one.")
+            .with_parameter(Parameter::string("ssid"))
+            .with_parameter(Parameter::string("key")),
+        ))
+        .with_command(with_timeout(
+            Command::new("remove", |ctx, args| {
+                execute(
+                    ctx,
+                    args,
+                    "network remove",
+                    &["ssid", "timeout", "duration"],
+                );
+            })
+            .with_help("Remove a network from the list.")
+            .with_parameter(Parameter::string("ssid")),
+        ))
 }
 
-fn network_add(ctrl: Option<&Controller>, mut args: Args) {
-    let ctrl = ctrl.unwrap();
-    let ssid = args.get_string("ssid").unwrap().unwrap();
-    let key = args.get_string("key").unwrap().unwrap();
-    let result = ctrl.set_wifi_network(ssid, key);
-    match result {
-        Ok(()) => {}
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn sync_group() -> Group<Context> {
+    Group::new("sync")
+        .with_help("Sync data from device, with a timeout as '5m' or '--timeout 5m'.")
+        .with_command(with_timeout(
+            Command::new("files", |ctx, args| {
+                execute(ctx, args, "sync files", TIMEOUT);
+            })
+            .with_help("Sync file list, in the background with '&'."),
+        ))
+        .with_command(with_timeout(
+            Command::new("tags", |ctx, args| execute(ctx, args, "sync tags", TIMEOUT))
+                .with_help("Sync tags of current and child directory, in the background with '&'."),
+        ))
 }
 
-fn network_remove(ctrl: Option<&Controller>, mut args: Args) {
-    let ctrl = ctrl.unwrap();
-    let ssid = args.get_string("ssid").unwrap().unwrap();
-    let result = ctrl.delete_wifi_network(ssid);
-    match result {
-        Ok(()) => {}
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
+fn fs_group() -> Group<Context> {
+    Group::new("fs")
+        .with_help("Access file system.")
+        .with_command(
+            Command::new("pwd", |ctx, args| execute(ctx, args, "fs pwd", &[]))
+                .with_help("Print current directory."),
+        )
+        .with_command(
+            Command::new("cd", |ctx, args| execute(ctx, args, "fs cd", &["dir"]))
+                .with_help("Change directory, e.g. 'Music/Rock', '../Jazz', '/' or '~'.")
+                .with_parameter(Parameter::string("dir")),
+        )
+        .with_command(
+            Command::new("ls", |ctx, args| execute(ctx, args, "fs ls", &[]))
+                .with_help("Print directory content."),
+        )
+        .with_command(
+            Command::new("find", |ctx, args| {
+                execute(ctx, args, "fs find", &["pattern", "mode", "option", "dir"]);
+            })
+            .with_help(
+                "Find directories and tracks by name, ignoring case: <pattern> [glob|regex] \
+                 [--in <dir>]. Searches the whole file list unless a directory is given.",
+            )
+            .with_parameter(Parameter::string("pattern"))
+            .with_optional_parameter(Parameter::string("mode"))
+            .with_optional_parameter(Parameter::string("option"))
+            .with_optional_parameter(Parameter::string("dir")),
+        )
+        .with_command(
+            Command::new("tree", |ctx, args| {
+                execute(ctx, args, "fs tree", &["dir", "option", "depth"]);
+            })
+            .with_help("Print the directory tree: [dir] [--depth N].")
+            .with_optional_parameter(Parameter::string("dir"))
+            .with_optional_parameter(Parameter::string("option"))
+            .with_optional_parameter(Parameter::string("depth")),
+        )
 }
 
-fn sync_files(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.sync_files();
-    match result {
-        Ok(()) => {}
-        Err(e) => println!("{}", e.to_string().bold()),
-    }
-}
+/// Whether the shell waits at the prompt, as opposed to running a command.
+static AT_PROMPT: AtomicBool = AtomicBool::new(true);
 
-fn sync_tags(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.sync_tags();
-    match result {
-        Ok(()) => {}
-        Err(e) => println!("{}", e.to_string().bold()),
+/// Prints a connection change on its own line above the prompt. The line
+/// being edited is not known here, so only the prompt is redrawn. While a
+/// command runs, the line goes between its output instead.
+fn status(prompt: &str, connected: bool) {
+    let mut out = io::stdout().lock();
+    let line = if connected {
+        "device connected".green()
+    } else {
+        "device disconnected".yellow()
+    };
+    if !out.is_terminal() {
+        writeln!(out, "{line}").ok();
+        return;
     }
-}
-
-fn fs_pwd(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.current_directory();
-    match result {
-        Ok(v) => println!("{v}"),
-        Err(e) => println!("{}", e.to_string().bold()),
+    // the line may hold the prompt or a progress bar, which is redrawn with
+    // its next step
+    write!(out, "\r\x1b[K{line}\n").ok();
+    if AT_PROMPT.load(Ordering::SeqCst) {
+        write!(out, "{prompt}").ok();
     }
+    out.flush().ok();
 }
 
-fn fs_cd(ctrl: Option<&Controller>, mut args: Args) {
-    let ctrl = ctrl.unwrap();
-    let dir = args.get_string("dir").unwrap().unwrap();
-    let result = ctrl.change_directory(&dir);
-    match result {
-        Ok(()) => {}
-        Err(e) => println!("{}", e.to_string().bold()),
+/// Runs the command `name` with the parameters present in `args` through
+/// [`command::execute`], so the shell accepts exactly what `-c` and scripts
+/// accept.
+fn execute(ctx: Option<&Context>, mut args: Args, name: &str, params: &[&str]) {
+    let mut words: Vec<String> = name.split(' ').map(str::to_owned).collect();
+    for param in params {
+        words.extend(args.get_string(param).unwrap());
     }
+    run(ctx, |ctx| command::execute(ctx, &words));
 }
 
-fn fs_ls(ctrl: Option<&Controller>, _: Args) {
-    let ctrl = ctrl.unwrap();
-    let result = ctrl.directory_content();
-    match result {
-        Ok(content) => {
-            for d in content.dirs {
-                println!("<{d}>");
-            }
-            if let Some(c) = content.cover {
-                println!("#{c}#");
-            }
-            for t in content.tracks {
-                println!("{t}");
-            }
-        }
-        Err(e) => println!("{}", e.to_string().bold()),
+/// Runs a shell command and reports its error. A Ctrl-C that no wait has
+/// seen is forgotten when the shell returns to the prompt.
+fn run(ctx: Option<&Context>, f: impl FnOnce(&Context) -> Result<()>) {
+    let ctx = ctx.unwrap();
+    AT_PROMPT.store(false, Ordering::SeqCst);
+    if let Err(e) = f(ctx) {
+        ctx.print_error(&e);
     }
+    ctx.ctrl.take_interrupt();
+    AT_PROMPT.store(true, Ordering::SeqCst);
 }
@@ -0,0 +1,275 @@
+use std::cmp;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::{Log, Metadata, Record};
+use simplelog::{
+    ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode, ThreadLogMode, WriteLogger,
+};
+
+/// Modules logged unless a rule says otherwise.
+const ENABLED: [&str; 3] = ["audio_cli", "audio_backend", "smart_repl"];
+
+/// Modules of the above that are too chatty to be logged by default.
+const DISABLED: [&str; 2] = ["audio_backend::com::mdns", "audio_backend::com::websocket"];
+
+/// Level of the log file unless another one is given.
+pub(crate) const DEFAULT_FILE_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// Size at which the log file is rotated.
+const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Number of rotated log files kept besides the current one.
+const KEEP_FILES: usize = 3;
+
+/// Logger whose level and module filter can be changed while the CLI runs.
+/// Records that pass the filter are written by a [`TermLogger`] and, if
+/// enabled, to a log file with its own level.
+struct Logger {
+    term: Box<TermLogger>,
+    state: Arc<RwLock<State>>,
+}
+
+struct State {
+    level: LevelFilter,
+    modules: Vec<(String, bool)>,
+    file: Option<LogFile>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    level: LevelFilter,
+    logger: Box<WriteLogger<RotatingFile>>,
+}
+
+/// File that is renamed to `<path>.1` once it grows beyond
+/// [`MAX_FILE_SIZE`], shifting older files up to `<path>.<KEEP_FILES>`.
+/// A record is written in several pieces, so they are buffered until the
+/// file is flushed after each record, and a record never spans two files.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    buf: Vec<u8>,
+}
+
+/// Changes the filter of the installed logger.
+pub(crate) struct LogControl {
+    state: Arc<RwLock<State>>,
+}
+
+/// Installs the logger. Modules in `disabled` replace the default list of
+/// modules that are not logged.
+pub(crate) fn init(
+    level: LevelFilter,
+    disabled: Option<&[String]>,
+    color: ColorChoice,
+) -> LogControl {
+    let modules = ENABLED
+        .into_iter()
+        .map(|module| (module.to_owned(), true))
+        .chain(match disabled {
+            Some(disabled) => disabled
+                .iter()
+                .map(|module| (module.clone(), false))
+                .collect(),
+            None => DISABLED
+                .into_iter()
+                .map(|module| (module.to_owned(), false))
+                .collect::<Vec<_>>(),
+        })
+        .collect();
+    let state = Arc::new(RwLock::new(State {
+        level,
+        modules,
+        file: None,
+    }));
+    let logger = Logger {
+        term: TermLogger::new(
+            LevelFilter::Trace,
+            ConfigBuilder::new().build(),
+            TerminalMode::Stdout,
+            color,
+        ),
+        state: state.clone(),
+    };
+    log::set_boxed_logger(Box::new(logger)).unwrap();
+    log::set_max_level(level);
+    LogControl { state }
+}
+
+impl LogControl {
+    pub(crate) fn level(&self) -> LevelFilter {
+        self.state.read().unwrap().level
+    }
+
+    pub(crate) fn set_level(&self, level: LevelFilter) {
+        let mut state = self.state.write().unwrap();
+        state.level = level;
+        state.update_max_level();
+    }
+
+    /// Returns the module rules, the most specific one for a target decides.
+    pub(crate) fn modules(&self) -> Vec<(String, bool)> {
+        self.state.read().unwrap().modules.clone()
+    }
+
+    pub(crate) fn set_module(&self, module: &str, enabled: bool) {
+        let mut state = self.state.write().unwrap();
+        if let Some(rule) = state.modules.iter_mut().find(|(m, _)| m == module) {
+            rule.1 = enabled;
+        } else {
+            state.modules.push((module.to_owned(), enabled));
+        }
+    }
+
+    pub(crate) fn file(&self) -> Option<(PathBuf, LevelFilter)> {
+        let state = self.state.read().unwrap();
+        state
+            .file
+            .as_ref()
+            .map(|file| (file.path.clone(), file.level))
+    }
+
+    /// Starts writing the log to `path` at `level` in addition to the
+    /// terminal, replacing any previous log file.
+    pub(crate) fn set_file(&self, path: &Path, level: LevelFilter) -> io::Result<()> {
+        let file = RotatingFile::open(path)?;
+        let config = ConfigBuilder::new()
+            .set_time_format_rfc3339()
+            .set_thread_level(LevelFilter::Error)
+            .set_thread_mode(ThreadLogMode::Names)
+            .set_target_level(LevelFilter::Error)
+            .build();
+        let mut state = self.state.write().unwrap();
+        state.file = Some(LogFile {
+            path: path.to_owned(),
+            level,
+            logger: WriteLogger::new(LevelFilter::Trace, config, file),
+        });
+        state.update_max_level();
+        Ok(())
+    }
+
+    pub(crate) fn close_file(&self) {
+        let mut state = self.state.write().unwrap();
+        if let Some(file) = state.file.take() {
+            file.logger.flush();
+        }
+        state.update_max_level();
+    }
+}
+
+impl State {
+    fn update_max_level(&self) {
+        log::set_max_level(
+            self.file
+                .as_ref()
+                .map_or(self.level, |file| cmp::max(self.level, file.level)),
+        );
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, level: LevelFilter) -> bool {
+        let target = metadata.target();
+        metadata.level() <= level
+            && self
+                .modules
+                .iter()
+                .filter(|(module, _)| {
+                    target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+                })
+                .max_by_key(|(module, _)| module.len())
+                .is_some_and(|(_, enabled)| *enabled)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let state = self.state.read().unwrap();
+        state.enabled(metadata, state.level)
+            || state
+                .file
+                .as_ref()
+                .is_some_and(|file| state.enabled(metadata, file.level))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let state = self.state.read().unwrap();
+        if state.enabled(record.metadata(), state.level) {
+            self.term.log(record);
+        }
+        if let Some(file) = &state.file
+            && state.enabled(record.metadata(), file.level)
+        {
+            file.logger.log(record);
+            file.logger.flush();
+        }
+    }
+
+    fn flush(&self) {
+        self.term.flush();
+        if let Some(file) = &self.state.read().unwrap().file {
+            file.logger.flush();
+        }
+    }
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+            buf: Vec::new(),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP_FILES).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        let buf = mem::take(&mut self.buf);
+        *self = Self::open(&self.path)?;
+        self.buf = buf;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        if self.size > 0 && self.size + self.buf.len() as u64 > MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(&self.buf)?;
+        self.size += self.buf.len() as u64;
+        self.buf.clear();
+        self.file.flush()
+    }
+}
@@ -1,27 +1,169 @@
 #![allow(clippy::print_stdout)]
+mod command;
+mod config;
+mod context;
 mod control;
+mod device;
 mod interface;
+mod logger;
+mod options;
+mod output;
+#[cfg(feature = "simulator")]
+mod simulator;
 
-use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+use std::env;
+use std::io;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
-use crate::control::Controller;
+use backend::Backend;
+use simplelog::{ColorChoice, LevelFilter};
+
+use crate::config::Config;
+use crate::context::Context;
+use crate::control::{Controller, Error};
+use crate::device::Device;
 use crate::interface::Cli;
+use crate::options::{Options, USAGE};
+use crate::output::Format;
+#[cfg(feature = "simulator")]
+use crate::simulator::{Setup, Simulator};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() -> ExitCode {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            let mut stderr = io::stderr().lock();
+            output::write(&mut stderr, Format::Text, &e).ok();
+            writeln!(stderr, "{USAGE}").ok();
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    if options.help {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    let config_path = options
+        .config
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(Config::default_path);
+    let config = match config_path.as_deref().map(Config::load).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            output::write(&mut io::stderr().lock(), Format::Text, &e).ok();
+            return ExitCode::from(e.exit_code());
+        }
+    };
+
+    if let Some(color) = config.color() {
+        colored::control::set_override(color);
+    }
+    let log = logger::init(
+        log_level(&options, &config),
+        config.log_ignore(),
+        match config.color() {
+            Some(true) => ColorChoice::Always,
+            Some(false) => ColorChoice::Never,
+            None => ColorChoice::Auto,
+        },
+    );
+    if let Some(path) = options.log_file.as_deref().or(config.log_file())
+        && let Err(e) = log.set_file(
+            Path::new(path),
+            config
+                .log_file_level()
+                .unwrap_or(logger::DEFAULT_FILE_LEVEL),
+        )
+    {
+        let e = Error::from(e);
+        output::write(&mut io::stderr().lock(), Format::Text, &e).ok();
+        return ExitCode::from(e.exit_code());
+    }
+    let device = match device(options.simulate.as_deref()) {
+        Ok(device) => device,
+        Err(e) => {
+            output::write(&mut io::stderr().lock(), Format::Text, &e).ok();
+            return ExitCode::from(e.exit_code());
+        }
+    };
+    let ctx = Context::new(
+        Controller::new(device),
+        log,
+        config,
+        config_path,
+        options.interactive(),
+    );
+    if let Some(format) = options.format {
+        ctx.set_format(format);
+    }
+    ctrlc::set_handler(ctx.ctrl.interrupter()).unwrap();
+
+    if options.interactive() {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("VERSION"));
+    } else {
+        // the device may not have been found yet when the first request is made
+        ctx.ctrl.set_connect_timeout(CONNECT_TIMEOUT);
+    }
+    if options.wait_connected
+        && let Err(e) = ctx.ctrl.wait_connected(options.connect_timeout)
+    {
+        ctx.print_error(&e);
+        return ExitCode::from(e.exit_code());
+    }
+    if options.interactive() {
+        Cli::new(&ctx).run();
+        return ExitCode::SUCCESS;
+    }
+
+    let result = if let Some(path) = &options.script {
+        command::source(&ctx, path, options.keep_going)
+    } else {
+        command::execute(&ctx, options.command.as_deref().unwrap_or_default())
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            ctx.print_error(&e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// Returns the log level from the configuration, or a default that depends
+/// on the mode, adjusted by `-v` and `-q`.
+fn log_level(options: &Options, config: &Config) -> LevelFilter {
+    let mut level = config.log_level().unwrap_or(if options.interactive() {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Warn
+    });
+    for _ in 0..options.verbosity {
+        level = level.increment_severity();
+    }
+    for _ in options.verbosity..0 {
+        level = level.decrement_severity();
+    }
+    level
+}
 
-fn main() {
-    TermLogger::init(
-        LevelFilter::Info,
-        ConfigBuilder::new()
-            .add_filter_allow_str("audio_cli")
-            .add_filter_allow_str("audio_backend")
-            .add_filter_allow_str("smart_repl")
-            .add_filter_ignore_str("audio_backend::com::mdns")
-            .add_filter_ignore_str("audio_backend::com::websocket")
-            .build(),
-        TerminalMode::Stdout,
-        ColorChoice::Auto,
-    )
-    .unwrap();
-    let ctrl = Controller::new();
-    println!("{} {}", env!("CARGO_PKG_NAME"), env!("VERSION"));
-    Cli::new(&ctrl).run();
+/// Returns the real device, or the simulated one if `--simulate` was given
+/// with the faults in `simulate`.
+fn device(simulate: Option<&str>) -> Result<Box<dyn Device>, Error> {
+    match simulate {
+        None => Ok(Box::new(Backend::new())),
+        #[cfg(feature = "simulator")]
+        Some("") => Ok(Box::new(Simulator::new(Setup::default()))),
+        #[cfg(feature = "simulator")]
+        Some(path) => Ok(Box::new(Simulator::new(Setup::load(Path::new(path))?))),
+        #[cfg(not(feature = "simulator"))]
+        Some(_) => Err(Error::InvalidArgument(
+            "--simulate needs a build with the simulator feature".into(),
+        )),
+    }
 }
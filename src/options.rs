@@ -0,0 +1,123 @@
+use std::iter;
+use std::time::Duration;
+
+use crate::command;
+use crate::control::{Error, Result};
+use crate::output::Format;
+
+pub(crate) const USAGE: &str = "\
+usage: audio-cli [options] [command...]
+
+Without a command an interactive shell is started.
+
+options:
+  -c, --command <line>   run a single command line and exit
+  -s, --script <file>    run the commands of a script file and exit
+  -k, --keep-going       do not stop the script at the first error
+  -f, --format <format>  output format: text (default) or json
+  -v, --verbose          log more, may be repeated
+  -q, --quiet            log less, may be repeated
+  -l, --log-file <file>  also write the log to a rotating file
+      --config <file>    configuration file, by default
+                         $XDG_CONFIG_HOME/audio-cli/config.toml
+  -w, --wait-connected   wait for the device before running anything
+      --wait-connected=<timeout>
+                         the same, but fail after the timeout
+      --simulate[=<file>]
+                         use a simulated device, with the canned data,
+                         delays and faults of a TOML file, failing the
+                         requests in turn with the faults none, lost, late,
+                         error, disconnect or abort (needs the simulator
+                         feature)
+  -h, --help             print this help
+
+exit status:
+    0  success
+    1  I/O error
+    2  invalid command or argument
+    3  timeout waiting for a reply or for the device
+    4  device not connected
+    5  error reported by the device, sync aborted by the device or
+       still running after an interrupt
+  130  interrupted by Ctrl-C";
+
+#[derive(Default)]
+pub(crate) struct Options {
+    pub(crate) command: Option<Vec<String>>,
+    pub(crate) script: Option<String>,
+    pub(crate) keep_going: bool,
+    pub(crate) format: Option<Format>,
+    pub(crate) config: Option<String>,
+    /// Number of `-v` minus number of `-q`.
+    pub(crate) verbosity: i32,
+    pub(crate) log_file: Option<String>,
+    /// Wait for the device before the first command, without a limit if
+    /// `connect_timeout` is not set.
+    pub(crate) wait_connected: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Setup file of the simulated device, empty for the defaults, `None` to
+    /// use the real device.
+    pub(crate) simulate: Option<String>,
+    pub(crate) help: bool,
+}
+
+impl Options {
+    pub(crate) fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--command" => {
+                    let line = args.next().ok_or_else(|| missing_value(&arg))?;
+                    options.command = Some(command::split(&line)?);
+                }
+                "-s" | "--script" => {
+                    options.script = Some(args.next().ok_or_else(|| missing_value(&arg))?);
+                }
+                "-k" | "--keep-going" => options.keep_going = true,
+                "-f" | "--format" => {
+                    options.format = Some(args.next().ok_or_else(|| missing_value(&arg))?.parse()?);
+                }
+                "--config" => {
+                    options.config = Some(args.next().ok_or_else(|| missing_value(&arg))?);
+                }
+                "-w" | "--wait-connected" => options.wait_connected = true,
+                "-v" | "--verbose" => options.verbosity += 1,
+                "-q" | "--quiet" => options.verbosity -= 1,
+                "-l" | "--log-file" => {
+                    options.log_file = Some(args.next().ok_or_else(|| missing_value(&arg))?);
+                }
+                "-h" | "--help" => options.help = true,
+                "--simulate" => options.simulate = Some(String::new()),
+                _ if arg.starts_with("--wait-connected=") => {
+                    let timeout = &arg["--wait-connected=".len()..];
+                    options.wait_connected = true;
+                    options.connect_timeout = Some(command::parse_duration(timeout)?);
+                }
+                _ if arg.starts_with("--simulate=") => {
+                    options.simulate = Some(arg["--simulate=".len()..].to_owned());
+                }
+                _ if arg.starts_with('-') => {
+                    return Err(Error::InvalidArgument(format!("unknown option: {arg}")));
+                }
+                _ => {
+                    options.command = Some(iter::once(arg).chain(args.by_ref()).collect());
+                }
+            }
+        }
+        if options.command.is_some() && options.script.is_some() {
+            return Err(Error::InvalidArgument(
+                "a command and a script cannot be given together".into(),
+            ));
+        }
+        Ok(options)
+    }
+
+    pub(crate) fn interactive(&self) -> bool {
+        self.command.is_none() && self.script.is_none()
+    }
+}
+
+fn missing_value(option: &str) -> Error {
+    Error::InvalidArgument(format!("missing value for option {option}"))
+}
@@ -0,0 +1,916 @@
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::{IsTerminal as _, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use backend::{About, Connection, DirectoryContent, Memory, Network, SPIFlash};
+use colored::Colorize as _;
+use serde_json::{Map, Value, json};
+use simplelog::LevelFilter;
+
+use crate::control::{ConnectionState, Error, Job, JobState, RequestClass};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Output of a command, rendered either as human readable text or as JSON.
+pub(crate) trait Render {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()>;
+    fn json(&self) -> Value;
+}
+
+pub(crate) fn write(
+    w: &mut dyn Write,
+    format: Format,
+    value: &(impl Render + ?Sized),
+) -> io::Result<()> {
+    match format {
+        Format::Text => value.text(w),
+        Format::Json => writeln!(w, "{}", value.json()),
+    }
+}
+
+/// Renders `value` into a buffer, so that it is written in one piece and
+/// cannot be split by log records from other threads.
+pub(crate) fn render(format: Format, value: &(impl Render + ?Sized)) -> Vec<u8> {
+    let mut buf = Vec::new();
+    // writing into a Vec cannot fail
+    write(&mut buf, format, value).unwrap();
+    buf
+}
+
+pub(crate) struct Versions(pub(crate) Vec<(String, String)>);
+
+pub(crate) struct AccessPointMode(pub(crate) bool);
+
+pub(crate) struct Settings(pub(crate) Vec<(String, String)>);
+
+pub(crate) struct LogLevel(pub(crate) LevelFilter);
+
+pub(crate) struct LogFilter {
+    pub(crate) level: LevelFilter,
+    pub(crate) file: Option<(PathBuf, LevelFilter)>,
+    pub(crate) modules: Vec<(String, bool)>,
+}
+
+pub(crate) struct Directory(pub(crate) String);
+
+/// A directory with its track count, cover and subdirectories.
+pub(crate) struct Tree {
+    pub(crate) name: String,
+    pub(crate) tracks: usize,
+    pub(crate) cover: Option<String>,
+    pub(crate) dirs: Vec<Tree>,
+}
+
+/// The connection state together with the connection mode, which is only
+/// known while the device is connected.
+pub(crate) struct ConnectionStatus(pub(crate) ConnectionState, pub(crate) Option<Connection>);
+
+pub(crate) struct TimeoutList(pub(crate) Vec<(RequestClass, Duration)>);
+
+impl Render for Format {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{self}")
+    }
+
+    fn json(&self) -> Value {
+        json!({ "format": self.to_string() })
+    }
+}
+
+impl Render for Versions {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        let max = self.0.iter().fold(0, |m, (n, _)| cmp::max(m, n.len() + 1));
+        for (n, v) in &self.0 {
+            writeln!(w, "{:max$} {v}", n.clone() + ":")?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        Value::Object(
+            self.0
+                .iter()
+                .map(|(n, v)| (n.clone(), Value::from(v.clone())))
+                .collect::<Map<_, _>>(),
+        )
+    }
+}
+
+impl Render for AccessPointMode {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", if self.0 { "on" } else { "off" })
+    }
+
+    fn json(&self) -> Value {
+        json!({ "auto": self.0 })
+    }
+}
+
+impl Render for Settings {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (key, value) in &self.0 {
+            writeln!(w, "{key} = {value}")?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        Value::Object(
+            self.0
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::from(value.clone())))
+                .collect::<Map<_, _>>(),
+        )
+    }
+}
+
+impl Render for LogLevel {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", level(self.0))
+    }
+
+    fn json(&self) -> Value {
+        json!({ "level": level(self.0) })
+    }
+}
+
+impl Render for LogFilter {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "level:   {}", level(self.level))?;
+        match &self.file {
+            Some((path, file_level)) => {
+                writeln!(w, "file:    {} ({})", path.display(), level(*file_level))?;
+            }
+            None => writeln!(w, "file:    off")?,
+        }
+        writeln!(w, "modules:")?;
+        let max = self
+            .modules
+            .iter()
+            .fold(0, |m, (module, _)| cmp::max(m, module.len()));
+        for (module, enabled) in &self.modules {
+            writeln!(
+                w,
+                "   {module:max$} {}",
+                if *enabled { "on" } else { "off" }
+            )?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "level": level(self.level),
+            "file": self.file.as_ref().map(|(path, file_level)| json!({
+                "path": path.display().to_string(),
+                "level": level(*file_level),
+            })),
+            "modules": self
+                .modules
+                .iter()
+                .map(|(module, enabled)| (module.clone(), Value::from(*enabled)))
+                .collect::<Map<_, _>>(),
+        })
+    }
+}
+
+impl Render for Directory {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", self.0)
+    }
+
+    fn json(&self) -> Value {
+        json!({ "directory": self.0 })
+    }
+}
+
+impl Render for TimeoutList {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for (class, timeout) in &self.0 {
+            writeln!(w, "{:8} {}", format!("{class}:"), duration(*timeout))?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        Value::Object(
+            self.0
+                .iter()
+                .map(|(class, timeout)| (class.to_string(), Value::from(duration(*timeout))))
+                .collect::<Map<_, _>>(),
+        )
+    }
+}
+
+impl Render for ConnectionStatus {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        let ConnectionStatus(state, connection) = self;
+        write!(
+            w,
+            "{}",
+            if state.connected {
+                "Connected"
+            } else {
+                "Disconnected"
+            }
+        )?;
+        match state.since {
+            Some(since) => writeln!(w, " for {}", clock(since))?,
+            None => writeln!(w)?,
+        }
+        if let Some(connection) = connection {
+            writeln!(w, "Mode: {}", connection.mode)?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        let ConnectionStatus(state, connection) = self;
+        json!({
+            "connected": state.connected,
+            "since": state.since.map(|since| since.as_secs()),
+            "mode": connection.as_ref().map(|connection| connection.mode.to_string()),
+        })
+    }
+}
+
+impl Render for About {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "Project:   {}", self.project)?;
+        writeln!(w, "Version:   {}", self.version)?;
+        writeln!(w, "ESP-IDF:   {}", self.esp_idf)
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "project": self.project,
+            "version": self.version,
+            "esp_idf": self.esp_idf,
+        })
+    }
+}
+
+impl Render for Memory {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "heap")?;
+        writeln!(
+            w,
+            "   total:        {:3} KiB",
+            self.heap.allocated / 1024 + self.heap.free / 1024
+        )?;
+        writeln!(w, "   allocated:    {:3} KiB", self.heap.allocated / 1024)?;
+        writeln!(w, "   free:         {:3} KiB", self.heap.free / 1024)?;
+        writeln!(
+            w,
+            "   minimum free: {:3} KiB",
+            self.heap.minimum_free / 1024
+        )
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "heap": {
+                "total": self.heap.allocated + self.heap.free,
+                "allocated": self.heap.allocated,
+                "free": self.heap.free,
+                "minimum_free": self.heap.minimum_free,
+            }
+        })
+    }
+}
+
+impl Render for SPIFlash {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "files")?;
+        for f in &self.files {
+            writeln!(w, "   {} {:6} {}", f.md5, f.size, f.name)?;
+        }
+        writeln!(w, "total: {:3} KiB", self.total / 1024)?;
+        writeln!(w, "free:  {:3} KiB", self.free / 1024)
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "files": self
+                .files
+                .iter()
+                .map(|f| json!({ "md5": f.md5, "size": f.size, "name": f.name }))
+                .collect::<Vec<_>>(),
+            "total": self.total,
+            "free": self.free,
+        })
+    }
+}
+
+impl Render for [Network] {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for network in self {
+            //writeln!(w, "{} {}", '\u{1f6dc}', network.ssid)?;
+            match network.rssi {
+                3 => writeln!(w, "\u{1f7e2} {}", network.ssid)?,
+                2 => writeln!(w, "\u{1f7e1} {}", network.ssid)?,
+                1 => writeln!(w, "\u{1f534} {}", network.ssid)?,
+                _ => writeln!(w, "? {}", network.ssid)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        self.iter()
+            .map(|network| json!({ "ssid": network.ssid, "rssi": network.rssi }))
+            .collect()
+    }
+}
+
+impl Render for [String] {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for s in self {
+            writeln!(w, "{s}")?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        self.iter().cloned().map(Value::from).collect()
+    }
+}
+
+impl Render for DirectoryContent {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for d in &self.dirs {
+            writeln!(w, "<{d}>")?;
+        }
+        if let Some(c) = &self.cover {
+            writeln!(w, "#{c}#")?;
+        }
+        for t in &self.tracks {
+            writeln!(w, "{t}")?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "dirs": self.dirs,
+            "cover": self.cover,
+            "tracks": self.tracks,
+        })
+    }
+}
+
+impl Render for Job {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        write!(w, "[{}] {:5} ", self.id, self.class.to_string())?;
+        match &self.state {
+            JobState::Waiting => writeln!(w, "waiting"),
+            JobState::Running(None) => writeln!(w, "running"),
+            JobState::Running(Some((n, t))) => writeln!(w, "running {n}/{t}"),
+            JobState::Done => writeln!(w, "{}", "done".green()),
+            JobState::Failed(e) => writeln!(w, "{} {e}", "failed:".red()),
+        }
+    }
+
+    fn json(&self) -> Value {
+        let mut job = json!({ "id": self.id, "class": self.class.to_string() });
+        let (state, progress, error) = match &self.state {
+            JobState::Waiting => ("waiting", None, None),
+            JobState::Running(progress) => ("running", *progress, None),
+            JobState::Done => ("done", None, None),
+            JobState::Failed(e) => ("failed", None, Some(e.to_string())),
+        };
+        job["state"] = Value::from(state);
+        if let Some((n, t)) = progress {
+            job["progress"] = json!({ "done": n, "total": t });
+        }
+        if let Some(error) = error {
+            job["error"] = Value::from(error);
+        }
+        job
+    }
+}
+
+impl Render for [Job] {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        for job in self {
+            job.text(w)?;
+        }
+        Ok(())
+    }
+
+    fn json(&self) -> Value {
+        self.iter().map(Render::json).collect()
+    }
+}
+
+impl Render for Tree {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.line(w)?;
+        self.children(w, "")
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "tracks": self.tracks,
+            "cover": self.cover,
+            "dirs": self.dirs.iter().map(Render::json).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl Tree {
+    fn line(&self, w: &mut dyn Write) -> io::Result<()> {
+        write!(w, "<{}> ", self.name)?;
+        match self.tracks {
+            1 => write!(w, "1 track")?,
+            n => write!(w, "{n} tracks")?,
+        }
+        if let Some(c) = &self.cover {
+            write!(w, " #{c}#")?;
+        }
+        writeln!(w)
+    }
+
+    fn children(&self, w: &mut dyn Write, indent: &str) -> io::Result<()> {
+        for (i, dir) in self.dirs.iter().enumerate() {
+            let last = i + 1 == self.dirs.len();
+            write!(w, "{indent}{}", if last { "└── " } else { "├── " })?;
+            dir.line(w)?;
+            dir.children(
+                w,
+                &format!("{indent}{}", if last { "    " } else { "│   " }),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Render for Error {
+    fn text(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{}", self.to_string().bold())
+    }
+
+    fn json(&self) -> Value {
+        json!({ "error": self.to_string(), "code": self.exit_code() })
+    }
+}
+
+/// Progress display of a long running request. On a terminal a bar is
+/// redrawn in place, otherwise a line is printed for every 10 percent.
+pub(crate) struct Progress {
+    label: String,
+    enabled: bool,
+    tty: bool,
+    started: Instant,
+    drawn: bool,
+    next_percent: usize,
+}
+
+impl Progress {
+    const WIDTH: usize = 30;
+
+    pub(crate) fn new(label: String, enabled: bool) -> Self {
+        Self {
+            label,
+            enabled,
+            tty: io::stdout().is_terminal(),
+            started: Instant::now(),
+            drawn: false,
+            next_percent: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, n: usize, t: usize) {
+        if !self.enabled || t == 0 {
+            return;
+        }
+        // the backend may report more steps than the total it announced
+        let n = n.min(t);
+        let percent = n * 100 / t;
+        let mut out = io::stdout().lock();
+        if self.tty {
+            let filled = n * Self::WIDTH / t;
+            write!(
+                out,
+                "\r\x1b[K{} [{}{}] {n}/{t} {percent:3}% {}",
+                self.label,
+                "#".repeat(filled),
+                ".".repeat(Self::WIDTH - filled),
+                self.rate(n, t),
+            )
+            .ok();
+            out.flush().ok();
+            self.drawn = true;
+        } else if percent >= self.next_percent {
+            writeln!(out, "{}: {n}/{t} ({percent}%)", self.label).ok();
+            self.next_percent = (percent / 10 + 1) * 10;
+        }
+    }
+
+    pub(crate) fn finish(&mut self) {
+        if self.drawn {
+            writeln!(io::stdout()).ok();
+            self.drawn = false;
+        }
+    }
+
+    fn rate(&self, n: usize, t: usize) -> String {
+        let elapsed = self.started.elapsed();
+        let millis = elapsed.as_millis();
+        if n == 0 || millis == 0 {
+            return String::new();
+        }
+        let per_second = n as u128 * 1000 / millis;
+        let remaining = elapsed * u32::try_from(t - n).unwrap_or(u32::MAX)
+            / u32::try_from(n).unwrap_or(u32::MAX);
+        format!("{per_second}/s ETA {}", clock(remaining))
+    }
+}
+
+pub(crate) fn level(level: LevelFilter) -> String {
+    level.to_string().to_lowercase()
+}
+
+fn clock(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Formats a duration in the largest unit that represents it exactly, the
+/// inverse of `command::parse_duration`.
+pub(crate) fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if d.subsec_nanos() != 0 {
+        format!("{}ms", d.as_millis())
+    } else if secs != 0 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs != 0 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown output format: {s}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backend::{File, Heap};
+
+    use super::*;
+
+    fn text(value: &(impl Render + ?Sized)) -> String {
+        colored::control::set_override(false);
+        String::from_utf8(render(Format::Text, value)).unwrap()
+    }
+
+    fn json(value: &(impl Render + ?Sized)) -> String {
+        String::from_utf8(render(Format::Json, value)).unwrap()
+    }
+
+    fn tree(name: &str, tracks: usize, cover: Option<&str>, dirs: Vec<Tree>) -> Tree {
+        Tree {
+            name: name.to_owned(),
+            tracks,
+            cover: cover.map(str::to_owned),
+            dirs,
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&value| value.to_owned()).collect()
+    }
+
+    #[test]
+    fn nested_tree() {
+        let tree = tree(
+            "/",
+            0,
+            None,
+            vec![
+                tree(
+                    "Jazz",
+                    2,
+                    Some("cover.jpg"),
+                    vec![tree("Modal", 1, None, vec![])],
+                ),
+                tree("Música", 3, None, vec![tree("Niños", 0, None, vec![])]),
+            ],
+        );
+        assert_eq!(
+            text(&tree),
+            concat!(
+                "</> 0 tracks\n",
+                "├── <Jazz> 2 tracks #cover.jpg#\n",
+                "│   └── <Modal> 1 track\n",
+                "└── <Música> 3 tracks\n",
+                "    └── <Niños> 0 tracks\n",
+            )
+        );
+        assert_eq!(
+            json(&tree),
+            concat!(
+                r#"{"cover":null,"dirs":["#,
+                r#"{"cover":"cover.jpg","dirs":[{"cover":null,"dirs":[],"name":"Modal","tracks":1}],"#,
+                r#""name":"Jazz","tracks":2},"#,
+                r#"{"cover":null,"dirs":[{"cover":null,"dirs":[],"name":"Niños","tracks":0}],"#,
+                r#""name":"Música","tracks":3}"#,
+                r#"],"name":"/","tracks":0}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn tree_without_dirs() {
+        let tree = tree("Rock", 0, None, vec![]);
+        assert_eq!(text(&tree), "<Rock> 0 tracks\n");
+        assert_eq!(
+            json(&tree),
+            "{\"cover\":null,\"dirs\":[],\"name\":\"Rock\",\"tracks\":0}\n"
+        );
+    }
+
+    #[test]
+    fn version_list() {
+        let versions = Versions(vec![
+            ("audio-cli".into(), "0.1.0".into()),
+            ("backend".into(), "0.2.0".into()),
+        ]);
+        assert_eq!(text(&versions), "audio-cli: 0.1.0\nbackend:   0.2.0\n");
+        assert_eq!(
+            json(&versions),
+            "{\"audio-cli\":\"0.1.0\",\"backend\":\"0.2.0\"}\n"
+        );
+        assert_eq!(text(&Versions(vec![])), "");
+        assert_eq!(json(&Versions(vec![])), "{}\n");
+    }
+
+    #[test]
+    fn timeout_list() {
+        let timeouts = TimeoutList(vec![
+            (RequestClass::Info, Duration::from_secs(5)),
+            (RequestClass::Network, Duration::from_millis(1500)),
+            (RequestClass::Tags, Duration::from_mins(10)),
+        ]);
+        assert_eq!(
+            text(&timeouts),
+            "info:    5s\nnetwork: 1500ms\ntags:    10m\n"
+        );
+        assert_eq!(
+            json(&timeouts),
+            "{\"info\":\"5s\",\"network\":\"1500ms\",\"tags\":\"10m\"}\n"
+        );
+        assert_eq!(text(&TimeoutList(vec![])), "");
+        assert_eq!(json(&TimeoutList(vec![])), "{}\n");
+    }
+
+    #[test]
+    fn job_list() {
+        let job = |id, class, state| Job { id, class, state };
+        let jobs = [
+            job(1, RequestClass::Files, JobState::Waiting),
+            job(2, RequestClass::Tags, JobState::Running(Some((3, 7)))),
+            job(3, RequestClass::Tags, JobState::Running(None)),
+            job(4, RequestClass::Files, JobState::Done),
+            job(5, RequestClass::Tags, JobState::Failed(Error::Timeout)),
+        ];
+        assert_eq!(
+            text(&jobs[..]),
+            concat!(
+                "[1] files waiting\n",
+                "[2] tags  running 3/7\n",
+                "[3] tags  running\n",
+                "[4] files done\n",
+                "[5] tags  failed: timeout\n",
+            )
+        );
+        assert_eq!(
+            json(&jobs[..]),
+            concat!(
+                r#"[{"class":"files","id":1,"state":"waiting"},"#,
+                r#"{"class":"tags","id":2,"progress":{"done":3,"total":7},"state":"running"},"#,
+                r#"{"class":"tags","id":3,"state":"running"},"#,
+                r#"{"class":"files","id":4,"state":"done"},"#,
+                r#"{"class":"tags","error":"timeout","id":5,"state":"failed"}]"#,
+                "\n",
+            )
+        );
+        let jobs: [Job; 0] = [];
+        assert_eq!(text(&jobs[..]), "");
+        assert_eq!(json(&jobs[..]), "[]\n");
+    }
+
+    #[test]
+    fn string_list() {
+        let networks = strings(&["Home", "Café Olé"]);
+        assert_eq!(text(&networks[..]), "Home\nCafé Olé\n");
+        assert_eq!(json(&networks[..]), "[\"Home\",\"Café Olé\"]\n");
+        assert_eq!(text(&[] as &[String]), "");
+        assert_eq!(json(&[] as &[String]), "[]\n");
+    }
+
+    #[test]
+    fn errors() {
+        let errors = [
+            (Error::Timeout, "timeout", 3),
+            (Error::Aborted(None), "aborted", 5),
+            (Error::Aborted(Some((3, 6))), "aborted after 3/6", 5),
+            (
+                Error::Script {
+                    path: "sync.txt".into(),
+                    line: 2,
+                    error: Box::new(Error::Script {
+                        path: "tags.txt".into(),
+                        line: 5,
+                        error: Box::new(Error::Timeout),
+                    }),
+                },
+                "sync.txt:2: tags.txt:5: timeout",
+                3,
+            ),
+            (
+                Error::Script {
+                    path: "música.txt".into(),
+                    line: 1,
+                    error: Box::new(Error::Aborted(Some((1, 4)))),
+                },
+                "música.txt:1: aborted after 1/4",
+                5,
+            ),
+        ];
+        for (error, message, code) in errors {
+            assert_eq!(text(&error), format!("{message}\n"));
+            assert_eq!(
+                json(&error),
+                format!("{{\"code\":{code},\"error\":\"{message}\"}}\n")
+            );
+        }
+    }
+
+    #[test]
+    fn about() {
+        let about = About {
+            project: "audio-player".into(),
+            version: "1.2.0".into(),
+            esp_idf: "v5.1".into(),
+        };
+        assert_eq!(
+            text(&about),
+            "Project:   audio-player\nVersion:   1.2.0\nESP-IDF:   v5.1\n"
+        );
+        assert_eq!(
+            json(&about),
+            "{\"esp_idf\":\"v5.1\",\"project\":\"audio-player\",\"version\":\"1.2.0\"}\n"
+        );
+    }
+
+    #[test]
+    fn memory() {
+        let memory = Memory {
+            heap: Heap {
+                allocated: 120 * 1024,
+                free: 180 * 1024,
+                minimum_free: 95 * 1024,
+            },
+        };
+        assert_eq!(
+            text(&memory),
+            concat!(
+                "heap\n",
+                "   total:        300 KiB\n",
+                "   allocated:    120 KiB\n",
+                "   free:         180 KiB\n",
+                "   minimum free:  95 KiB\n",
+            )
+        );
+        assert_eq!(
+            json(&memory),
+            concat!(
+                r#"{"heap":{"allocated":122880,"free":184320,"#,
+                r#""minimum_free":97280,"total":307200}}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn spiflash() {
+        let mut flash = SPIFlash {
+            files: vec![File {
+                md5: "d41d8cd98f00b204e9800998ecf8427e".into(),
+                size: 2048,
+                name: "wifi.json".into(),
+            }],
+            total: 512 * 1024,
+            free: 500 * 1024,
+        };
+        assert_eq!(
+            text(&flash),
+            concat!(
+                "files\n",
+                "   d41d8cd98f00b204e9800998ecf8427e   2048 wifi.json\n",
+                "total: 512 KiB\n",
+                "free:  500 KiB\n",
+            )
+        );
+        assert_eq!(
+            json(&flash),
+            concat!(
+                r#"{"files":[{"md5":"d41d8cd98f00b204e9800998ecf8427e","name":"wifi.json","size":2048}],"#,
+                r#""free":512000,"total":524288}"#,
+                "\n",
+            )
+        );
+        flash.files.clear();
+        assert_eq!(text(&flash), "files\ntotal: 512 KiB\nfree:  500 KiB\n");
+        assert_eq!(
+            json(&flash),
+            "{\"files\":[],\"free\":512000,\"total\":524288}\n"
+        );
+    }
+
+    #[test]
+    fn network_list() {
+        let networks = [("Home", 3), ("Café Olé", 2), ("Garage", 1), ("Far", 0)]
+            .into_iter()
+            .map(|(ssid, rssi)| Network {
+                ssid: ssid.into(),
+                rssi,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            text(&networks[..]),
+            "\u{1f7e2} Home\n\u{1f7e1} Café Olé\n\u{1f534} Garage\n? Far\n"
+        );
+        assert_eq!(
+            json(&networks[..]),
+            concat!(
+                r#"[{"rssi":3,"ssid":"Home"},{"rssi":2,"ssid":"Café Olé"},"#,
+                r#"{"rssi":1,"ssid":"Garage"},{"rssi":0,"ssid":"Far"}]"#,
+                "\n",
+            )
+        );
+        assert_eq!(text(&[] as &[Network]), "");
+        assert_eq!(json(&[] as &[Network]), "[]\n");
+    }
+
+    #[test]
+    fn directory_content() {
+        let content = DirectoryContent {
+            dirs: strings(&["Jazz", "Música"]),
+            cover: Some("portada.jpg".into()),
+            tracks: strings(&["Canción de cuna.mp3", "Ñandú.mp3"]),
+        };
+        assert_eq!(
+            text(&content),
+            "<Jazz>\n<Música>\n#portada.jpg#\nCanción de cuna.mp3\nÑandú.mp3\n"
+        );
+        assert_eq!(
+            json(&content),
+            concat!(
+                r#"{"cover":"portada.jpg","dirs":["Jazz","Música"],"#,
+                r#""tracks":["Canción de cuna.mp3","Ñandú.mp3"]}"#,
+                "\n",
+            )
+        );
+        let empty = DirectoryContent {
+            dirs: vec![],
+            cover: None,
+            tracks: vec![],
+        };
+        assert_eq!(text(&empty), "");
+        assert_eq!(json(&empty), "{\"cover\":null,\"dirs\":[],\"tracks\":[]}\n");
+    }
+}
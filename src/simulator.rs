@@ -0,0 +1,445 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::result;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use backend::{
+    About, ChangeDirectory, Connection, DirectoryContent, Event, File, FileSync, Heap, Memory,
+    Mode, Network, SPIFlash, TagSync,
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::command;
+use crate::control::{Error, Result};
+use crate::device::Device;
+
+/// Fault injected into the handling of a request. The faults of a [`Setup`]
+/// apply to the requests in the order they are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Fault {
+    /// The request is handled normally.
+    None,
+    /// The reply never arrives.
+    Lost,
+    /// The reply arrives after [`Setup::late`].
+    Late,
+    /// The device reports an error instead of the reply, halfway through a
+    /// sync.
+    Error,
+    /// The device disconnects halfway and comes back after
+    /// [`Setup::reconnect`].
+    Disconnect,
+    /// A sync is aborted by the device halfway, other requests are handled
+    /// normally.
+    Abort,
+}
+
+/// Canned data, delays and faults of the simulated device, read from a TOML
+/// file. Everything missing in the file keeps its default:
+///
+/// ```toml
+/// delay = "200ms"
+/// faults = ["none", "late", "error"]
+/// networks = ["Home"]
+/// scan = [{ ssid = "Home", rssi = 3 }]
+/// about = { project = "audio-player", version = "1.0", esp_idf = "v5.1" }
+///
+/// [[tree]]
+/// path = "Jazz"
+/// cover = "cover.jpg"
+/// tracks = ["So What.mp3"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Setup {
+    /// Time the device takes for a reply or a sync step.
+    #[serde(deserialize_with = "duration")]
+    delay: Duration,
+    /// Time after which a late reply arrives.
+    #[serde(deserialize_with = "duration")]
+    late: Duration,
+    /// Time the device stays away after a disconnect.
+    #[serde(deserialize_with = "duration")]
+    reconnect: Duration,
+    faults: Vec<Fault>,
+    about: AboutData,
+    memory: MemoryData,
+    flash: FlashData,
+    scan: Vec<NetworkData>,
+    /// Networks configured on the device.
+    networks: Vec<String>,
+    /// Directories of the synced file list. The root directory has an empty
+    /// path and is added if it is missing.
+    tree: Vec<Dir>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AboutData {
+    project: String,
+    version: String,
+    esp_idf: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemoryData {
+    allocated: u32,
+    free: u32,
+    minimum_free: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlashData {
+    total: u32,
+    free: u32,
+    #[serde(default)]
+    files: Vec<FileData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileData {
+    name: String,
+    size: u32,
+    md5: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkData {
+    ssid: String,
+    rssi: u8,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Dir {
+    path: String,
+    cover: Option<String>,
+    tracks: Vec<String>,
+}
+
+/// Device without hardware that answers requests with canned data after a
+/// short delay, for trying the CLI and its error handling. It connects
+/// shortly after it has been created.
+pub(crate) struct Simulator {
+    setup: Setup,
+    /// Events to send, each after its delay, handled one after the other by
+    /// a thread like the device handles requests.
+    script: Sender<Vec<(Duration, Event)>>,
+    receiver: RefCell<Option<Receiver<Event>>>,
+    faults: RefCell<VecDeque<Fault>>,
+    access_point_mode: Cell<bool>,
+    networks: RefCell<Vec<String>>,
+    path: RefCell<Vec<String>>,
+}
+
+impl Setup {
+    /// Reads the setup from the TOML file at `path`.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut setup: Self = toml::from_str(&content)
+            .map_err(|e| Error::InvalidArgument(format!("{}: {e}", path.display())))?;
+        if !setup.tree.iter().any(|dir| dir.path.is_empty()) {
+            setup.tree.insert(0, Dir::default());
+        }
+        Ok(setup)
+    }
+
+    fn directory(&self, path: &[String]) -> Option<DirectoryContent> {
+        let path = path.join("/");
+        let dir = self.tree.iter().find(|dir| dir.path == path)?;
+        let prefix = if path.is_empty() { path } else { path + "/" };
+        Some(DirectoryContent {
+            dirs: self
+                .tree
+                .iter()
+                .filter_map(|dir| dir.path.strip_prefix(prefix.as_str()))
+                .filter(|name| !name.is_empty() && !name.contains('/'))
+                .map(str::to_owned)
+                .collect(),
+            cover: dir.cover.clone(),
+            tracks: dir.tracks.clone(),
+        })
+    }
+}
+
+impl Default for Setup {
+    fn default() -> Self {
+        let dir = |path: &str, cover: Option<&str>, tracks: &[&str]| Dir {
+            path: path.to_owned(),
+            cover: cover.map(str::to_owned),
+            tracks: tracks.iter().map(|&track| track.to_owned()).collect(),
+        };
+        Self {
+            delay: Duration::from_millis(200),
+            // longer than the default timeout of info and network requests
+            late: Duration::from_secs(5),
+            reconnect: Duration::from_secs(2),
+            faults: Vec::new(),
+            about: AboutData {
+                project: "audio-player".into(),
+                version: "simulated".into(),
+                esp_idf: "v5.1".into(),
+            },
+            memory: MemoryData {
+                allocated: 120 * 1024,
+                free: 180 * 1024,
+                minimum_free: 95 * 1024,
+            },
+            flash: FlashData {
+                total: 1024 * 1024,
+                free: 1000 * 1024,
+                files: vec![FileData {
+                    name: "wifi.json".into(),
+                    size: 2048,
+                    md5: "d41d8cd98f00b204e9800998ecf8427e".into(),
+                }],
+            },
+            scan: [("Home", 3), ("Café Olé", 2), ("Garage", 1)]
+                .into_iter()
+                .map(|(ssid, rssi)| NetworkData {
+                    ssid: ssid.into(),
+                    rssi,
+                })
+                .collect(),
+            networks: vec!["Home".into()],
+            tree: vec![
+                dir("", None, &[]),
+                dir(
+                    "Jazz",
+                    Some("cover.jpg"),
+                    &["So What.mp3", "Blue in Green.mp3"],
+                ),
+                dir("Rock", None, &["Paranoid.mp3"]),
+                dir(
+                    "Rock/Classic",
+                    Some("front.png"),
+                    &["Stairway to Heaven.mp3"],
+                ),
+                dir("Rock/Indie", None, &[]),
+                dir(
+                    "Música",
+                    Some("portada.jpg"),
+                    &["Canción de cuna.mp3", "Ñandú.mp3"],
+                ),
+            ],
+        }
+    }
+}
+
+impl Simulator {
+    pub(crate) fn new(setup: Setup) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let (script, scripts) = mpsc::channel::<Vec<(Duration, Event)>>();
+        thread::Builder::new()
+            .name("simulator".into())
+            .spawn(move || {
+                for (delay, event) in scripts.into_iter().flatten() {
+                    thread::sleep(delay);
+                    if sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        script.send(vec![(setup.delay, Event::Connected)]).unwrap();
+        Self {
+            script,
+            receiver: RefCell::new(Some(receiver)),
+            faults: RefCell::new(setup.faults.iter().copied().collect()),
+            access_point_mode: Cell::new(true),
+            networks: RefCell::new(setup.networks.clone()),
+            path: RefCell::new(Vec::new()),
+            setup,
+        }
+    }
+
+    /// Sends `events` in answer to a request, each after the delay of the
+    /// setup, with the next fault applied. `aborted` is the event ending an
+    /// aborted sync.
+    fn answer(&self, events: Vec<Event>, aborted: Option<Event>) {
+        let fault = self.faults.borrow_mut().pop_front().unwrap_or(Fault::None);
+        let delay = self.setup.delay;
+        let mut script: Vec<_> = events.into_iter().map(|event| (delay, event)).collect();
+        let half = script.len() / 2;
+        match fault {
+            Fault::None => {}
+            Fault::Lost => script.clear(),
+            Fault::Late => script[0].0 = self.setup.late,
+            Fault::Error => {
+                script.truncate(half);
+                script.push((delay, Event::Error(backend::Error)));
+            }
+            Fault::Disconnect => {
+                script.truncate(half);
+                script.push((delay, Event::Disconnected));
+                script.push((self.setup.reconnect, Event::Connected));
+            }
+            Fault::Abort => {
+                if let Some(aborted) = aborted {
+                    script.truncate(half);
+                    script.push((delay, aborted));
+                }
+            }
+        }
+        self.script.send(script).ok();
+    }
+}
+
+impl Device for Simulator {
+    fn receiver(&self) -> Option<Receiver<Event>> {
+        self.receiver.borrow_mut().take()
+    }
+
+    fn get_access_point_mode(&self) -> bool {
+        self.access_point_mode.get()
+    }
+
+    fn set_access_point_mode(&self, auto: bool) {
+        self.access_point_mode.set(auto);
+    }
+
+    fn get_info_connection(&self) {
+        let connection = Connection {
+            mode: Mode::Station,
+        };
+        self.answer(vec![Event::InfoConnection(connection)], None);
+    }
+
+    fn get_info_about(&self) {
+        let about = &self.setup.about;
+        let about = About {
+            project: about.project.clone(),
+            version: about.version.clone(),
+            esp_idf: about.esp_idf.clone(),
+        };
+        self.answer(vec![Event::InfoAbout(about)], None);
+    }
+
+    fn get_info_memory(&self) {
+        let memory = &self.setup.memory;
+        let memory = Memory {
+            heap: Heap {
+                allocated: memory.allocated,
+                free: memory.free,
+                minimum_free: memory.minimum_free,
+            },
+        };
+        self.answer(vec![Event::InfoMemory(memory)], None);
+    }
+
+    fn get_info_spiflash(&self) {
+        let flash = &self.setup.flash;
+        let flash = SPIFlash {
+            files: flash
+                .files
+                .iter()
+                .map(|file| File {
+                    md5: file.md5.clone(),
+                    size: file.size,
+                    name: file.name.clone(),
+                })
+                .collect(),
+            total: flash.total,
+            free: flash.free,
+        };
+        self.answer(vec![Event::InfoSPIFlash(flash)], None);
+    }
+
+    fn get_wifi_scan_result(&self) {
+        let networks = self
+            .setup
+            .scan
+            .iter()
+            .map(|network| Network {
+                ssid: network.ssid.clone(),
+                rssi: network.rssi,
+            })
+            .collect();
+        self.answer(vec![Event::WiFiScanResult(networks)], None);
+    }
+
+    fn get_wifi_network_list(&self) {
+        let networks = self.networks.borrow().clone();
+        self.answer(vec![Event::WiFiNetworkList(networks)], None);
+    }
+
+    fn set_wifi_network(&self, ssid: String, _key: String) {
+        let mut networks = self.networks.borrow_mut();
+        if !networks.contains(&ssid) {
+            networks.push(ssid);
+        }
+        self.answer(vec![Event::WiFiSetNetwork], None);
+    }
+
+    fn delete_wifi_network(&self, ssid: String) {
+        self.networks
+            .borrow_mut()
+            .retain(|network| *network != ssid);
+        self.answer(vec![Event::WiFiDeleteNetwork], None);
+    }
+
+    fn sync_files(&self) {
+        self.answer(
+            vec![
+                Event::FileSync(FileSync::Started),
+                Event::FileSync(FileSync::Completed),
+            ],
+            Some(Event::FileSync(FileSync::Aborted)),
+        );
+    }
+
+    fn sync_tags(&self) {
+        let total = self.setup.tree.iter().map(|dir| dir.tracks.len()).sum();
+        let events = [Event::TagSync(TagSync::Started)]
+            .into_iter()
+            .chain((1..=total).map(|n| Event::TagSync(TagSync::Step(n, total))))
+            .chain([Event::TagSync(TagSync::Completed)])
+            .collect();
+        self.answer(events, Some(Event::TagSync(TagSync::Aborted)));
+    }
+
+    fn current_directory(&self) -> Result<Vec<String>> {
+        Ok(self.path.borrow().clone())
+    }
+
+    /// Fails like the backend does, with [`Error::Backend`], if there is no
+    /// parent or no such child directory.
+    fn change_directory(&self, dir: ChangeDirectory<'_>) -> Result<()> {
+        let mut path = self.path.borrow_mut();
+        match dir {
+            ChangeDirectory::ToRoot => path.clear(),
+            ChangeDirectory::ToParent => {
+                path.pop().ok_or(Error::Backend(backend::Error))?;
+            }
+            ChangeDirectory::ToChild(name) => {
+                let mut child = path.clone();
+                child.push(name.to_owned());
+                if self.setup.directory(&child).is_none() {
+                    return Err(Error::Backend(backend::Error));
+                }
+                *path = child;
+            }
+        }
+        Ok(())
+    }
+
+    fn directory_content(&self) -> Result<DirectoryContent> {
+        Ok(self.setup.directory(&self.path.borrow()).unwrap())
+    }
+}
+
+/// Deserializes a duration written like `500ms` or `5s`.
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> result::Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    command::parse_duration(&value).map_err(serde::de::Error::custom)
+}